@@ -0,0 +1,647 @@
+//! AWS SDK for Rustの呼び出しをトレースするInterceptor。
+//!
+//! SDKのクライアントに [DatadogInterceptor] を登録すると、API呼び出し毎に `aws.<service>` (`aws.dynamodb` など)の
+//! クライアントSpanを作成し、以下をセットする。
+//! - `aws.service` / `aws.operation` / `aws.region`
+//! - `aws.requestId` / `http.status_code` / リトライ回数(`aws.retry_count`)
+//! - サービス毎のリソース名(DynamoDBのテーブル名、S3のバケット名、SQSのキュー名など)
+//!
+//! リソース名はSDKの型に依存しないように、送信直前のHTTPリクエスト(URLとボディ)から取り出す。
+//!
+//! また、以下の操作では送信するメッセージにトレース情報を埋め込む([crate::propagation] 参照)。
+//! 埋め込みもHTTPリクエストのボディを書き換える事で行う(署名前に書き換えるので署名は正しく計算される)。
+//! - SQS `SendMessage` / `SendMessageBatch`、SNS `Publish` / `PublishBatch`: 各メッセージの `_datadog` メッセージ属性
+//!   (バッチの場合、バッチ全体のサイズが上限を超えるエントリには埋め込まない)
+//! - EventBridge `PutEvents`: 各エントリの `detail._datadog`
+//! - Kinesis `PutRecord` / `PutRecords`: 各レコードのデータの `_datadog`
+//! `aws_sdk` featureを有効にした場合のみ使える。
+//!
+//! ## Example
+//! ```
+//! let config = aws_config::load_from_env().await;
+//! let dynamodb_config = aws_sdk_dynamodb::config::Builder::from(&config)
+//!     .interceptor(DatadogInterceptor)
+//!     .build();
+//! let client = aws_sdk_dynamodb::Client::from_conf(dynamodb_config);
+//! ```
+
+use crate::helper;
+use crate::propagation;
+use aws_smithy_http::body::SdkBody;
+use aws_smithy_http::operation::Metadata;
+use aws_smithy_runtime_api::box_error::BoxError;
+use aws_smithy_runtime_api::client::interceptors::context::{
+    BeforeSerializationInterceptorContextRef, BeforeTransmitInterceptorContextMut, BeforeTransmitInterceptorContextRef,
+    FinalizerInterceptorContextRef,
+};
+use aws_smithy_runtime_api::client::interceptors::Interceptor;
+use aws_smithy_runtime_api::client::orchestrator::HttpRequest;
+use aws_smithy_runtime_api::client::runtime_components::RuntimeComponents;
+use aws_smithy_types::config_bag::{ConfigBag, Storable, StoreReplace};
+use aws_types::region::Region;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use lambda_http::http::header::CONTENT_LENGTH;
+use serde_json::Value;
+use tracing::Span;
+
+const REQUEST_ID_HEADERS: [&str; 2] = ["x-amzn-requestid", "x-amz-request-id"];
+
+/// SDKのクライアントに登録するInterceptor
+#[derive(Debug, Default)]
+pub struct DatadogInterceptor;
+
+/// 呼び出し中のSpan。呼び出し毎のConfigBagに保持する
+#[derive(Debug, Clone)]
+struct SdkSpan {
+    span: Span,
+    attempts: u32,
+}
+
+impl Storable for SdkSpan {
+    type Storer = StoreReplace<Self>;
+}
+
+impl Interceptor for DatadogInterceptor {
+    fn name(&self) -> &'static str {
+        "DatadogInterceptor"
+    }
+
+    fn read_before_execution(
+        &self, _context: &BeforeSerializationInterceptorContextRef<'_>, cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let (service, operation) = match cfg.load::<Metadata>() {
+            Some(metadata) => (metadata.service().to_string(), metadata.name().to_string()),
+            None => ("unknown".to_string(), "unknown".to_string()),
+        };
+        let span = helper::aws_client_span(&service, &operation);
+        if let Some(region) = cfg.load::<Region>() {
+            helper::set_tag(&span, "aws.region", region.as_ref());
+            helper::set_tag(&span, "region", region.as_ref());
+        }
+        cfg.interceptor_state().store_put(SdkSpan { span, attempts: 0 });
+        Ok(())
+    }
+
+    fn read_before_transmit(
+        &self, context: &BeforeTransmitInterceptorContextRef<'_>, _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let Some(mut state) = cfg.load::<SdkSpan>().cloned() else {
+            return Ok(());
+        };
+        state.attempts += 1;
+        // リソース名は全ての試行で同じなので、初回のみセットする
+        if state.attempts == 1 {
+            let service = cfg
+                .load::<Metadata>()
+                .map(|m| m.service().to_string())
+                .unwrap_or_default();
+            for (key, value) in resource_tags(&service, context.request()) {
+                helper::set_tag(&state.span, key, &value);
+            }
+        }
+        cfg.interceptor_state().store_put(state);
+        Ok(())
+    }
+
+    fn modify_before_signing(
+        &self, context: &mut BeforeTransmitInterceptorContextMut<'_>, _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let (Some(state), Some(metadata)) = (cfg.load::<SdkSpan>(), cfg.load::<Metadata>()) else {
+            return Ok(());
+        };
+        let req = context.request_mut();
+        let Some(body) = req.body().bytes() else {
+            return Ok(());
+        };
+        let injected = match (metadata.service().to_lowercase().as_str(), metadata.name()) {
+            ("sqs", "SendMessage") => inject_message_attribute(body, &state.span, SQS_FORM_KEYS, None),
+            ("sqs", "SendMessageBatch") => {
+                inject_message_attribute(body, &state.span, SQS_FORM_KEYS, Some(SQS_BATCH_KEYS))
+            }
+            ("sns", "Publish") => inject_message_attribute(body, &state.span, SNS_FORM_KEYS, None),
+            ("sns", "PublishBatch") => inject_message_attribute(body, &state.span, SNS_FORM_KEYS, Some(SNS_BATCH_KEYS)),
+            ("eventbridge", "PutEvents") => inject_eventbridge(body, &state.span),
+            ("kinesis", "PutRecord") | ("kinesis", "PutRecords") => inject_kinesis(body, &state.span),
+            _ => None,
+        };
+        if let Some(new_body) = injected {
+            req.headers_mut().insert(CONTENT_LENGTH, new_body.len().into());
+            *req.body_mut() = SdkBody::from(new_body);
+        }
+        Ok(())
+    }
+
+    fn read_after_execution(
+        &self, context: &FinalizerInterceptorContextRef<'_>, _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let Some(state) = cfg.load::<SdkSpan>().cloned() else {
+            return Ok(());
+        };
+        let span = &state.span;
+        if let Some(res) = context.response() {
+            helper::record_client_response(span, res.status().as_u16(), res.headers());
+            let request_id = REQUEST_ID_HEADERS
+                .iter()
+                .find_map(|h| res.headers().get(*h).and_then(|v| v.to_str().ok()));
+            if let Some(request_id) = request_id {
+                helper::set_tag(span, "aws.requestId", request_id);
+            }
+        }
+        if let Some(Err(err)) = context.output_or_error() {
+            helper::record_client_error(span, &format!("{:?}", err));
+        }
+        helper::set_metric(span, "aws.retry_count", state.attempts.saturating_sub(1) as f64);
+        // ConfigBagから外してSpanを閉じる
+        cfg.interceptor_state().unset::<SdkSpan>();
+        Ok(())
+    }
+}
+
+/// サービス毎のリソース名のタグを、送信するHTTPリクエストから取り出す
+fn resource_tags(service: &str, req: &HttpRequest) -> Vec<(&'static str, String)> {
+    let body = req.body().bytes().unwrap_or_default();
+    match service.to_lowercase().as_str() {
+        "dynamodb" => json_field(body, "TableName")
+            .map(|v| vec![("tablename", v)])
+            .unwrap_or_default(),
+        "kinesis" => json_field(body, "StreamName")
+            .map(|v| vec![("streamname", v)])
+            .unwrap_or_default(),
+        "s3" => s3_bucket(req).map(|v| vec![("bucketname", v)]).unwrap_or_default(),
+        // SQS/SNSはプロトコル(JSON/Query)がバージョンにより異なるので両方見る
+        "sqs" => json_field(body, "QueueUrl")
+            .or_else(|| form_field(body, "QueueUrl"))
+            .and_then(|url| url.rsplit('/').next().map(str::to_string))
+            .map(|v| vec![("queuename", v)])
+            .unwrap_or_default(),
+        "sns" => json_field(body, "TopicArn")
+            .or_else(|| form_field(body, "TopicArn"))
+            .and_then(|arn| arn.rsplit(':').next().map(str::to_string))
+            .map(|v| vec![("topicname", v)])
+            .unwrap_or_default(),
+        _ => vec![],
+    }
+}
+
+fn json_field(body: &[u8], key: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+    value.get(key)?.as_str().map(str::to_string)
+}
+
+fn form_field(body: &[u8], key: &str) -> Option<String> {
+    form_urlencoded::parse(body)
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+/// 仮想ホスト形式(`{bucket}.s3.{region}.amazonaws.com`)とパス形式(`s3.{region}.amazonaws.com/{bucket}`)の両方に対応する
+fn s3_bucket(req: &HttpRequest) -> Option<String> {
+    let uri = req.uri();
+    let host = uri.host()?;
+    match host.split_once(".s3") {
+        Some((bucket, _)) if !bucket.is_empty() => Some(bucket.to_string()),
+        _ => uri
+            .path()
+            .trim_start_matches('/')
+            .split('/')
+            .next()
+            .filter(|b| !b.is_empty())
+            .map(str::to_string),
+    }
+}
+
+/// Queryプロトコルでのメッセージ属性のキー
+struct FormKeys {
+    /// メッセージ本文のキー
+    message: &'static str,
+    /// メッセージ属性のキーの接頭辞。`{prefix}.{N}.Name` のようになる
+    attribute_prefix: &'static str,
+}
+
+const SQS_FORM_KEYS: FormKeys = FormKeys {
+    message: "MessageBody",
+    attribute_prefix: "MessageAttribute",
+};
+const SNS_FORM_KEYS: FormKeys = FormKeys {
+    message: "Message",
+    attribute_prefix: "MessageAttributes.entry",
+};
+
+/// バッチ送信(`SendMessageBatch` / `PublishBatch`)でのエントリのキー
+struct BatchKeys {
+    /// JSONプロトコルでのエントリの配列のキー
+    json_entries: &'static str,
+    /// Queryプロトコルでのエントリのキーの接頭辞。`{prefix}.{N}.{message}` のようになる
+    form_entry_prefix: &'static str,
+}
+
+const SQS_BATCH_KEYS: BatchKeys = BatchKeys {
+    json_entries: "Entries",
+    form_entry_prefix: "SendMessageBatchRequestEntry",
+};
+const SNS_BATCH_KEYS: BatchKeys = BatchKeys {
+    json_entries: "PublishBatchRequestEntries",
+    form_entry_prefix: "PublishBatchRequestEntries.member",
+};
+
+/// SQS/SNSのメッセージ属性に `_datadog` を追加する。`batch` を指定した場合は各エントリに追加する。
+/// SQSはSDKのバージョンによりJSONプロトコルの場合があるので、その場合は `MessageAttributes` に追加する。
+/// 埋め込めなかったエントリはそのままにする。単一のメッセージで埋め込めなかった場合はNone。
+fn inject_message_attribute(body: &[u8], span: &Span, keys: FormKeys, batch: Option<BatchKeys>) -> Option<Vec<u8>> {
+    if let Ok(mut json) = serde_json::from_slice::<Value>(body) {
+        match batch {
+            None => {
+                let mut message_bytes = json_message_bytes(&json, &keys);
+                inject_json_attribute(&mut json, span, &mut message_bytes)?;
+            }
+            Some(batch) => {
+                let entries = json.get_mut(batch.json_entries)?.as_array_mut()?;
+                // バッチ全体のサイズにも上限があるので、全エントリの合計で判定する
+                let mut batch_bytes: usize = entries.iter().map(|e| json_message_bytes(e, &keys)).sum();
+                for entry in entries {
+                    let _ = inject_json_attribute(entry, span, &mut batch_bytes);
+                }
+            }
+        }
+        return serde_json::to_vec(&json).ok();
+    }
+
+    let mut pairs: Vec<(String, String)> = form_urlencoded::parse(body).into_owned().collect();
+    match batch {
+        None => {
+            let mut message_bytes = form_message_bytes(&pairs, "", &keys);
+            inject_form_attribute(&mut pairs, "", &keys, span, &mut message_bytes)?;
+        }
+        Some(batch) => {
+            let prefixes = form_entry_prefixes(&pairs, batch.form_entry_prefix);
+            let mut batch_bytes: usize = prefixes.iter().map(|p| form_message_bytes(&pairs, p, &keys)).sum();
+            for prefix in &prefixes {
+                let _ = inject_form_attribute(&mut pairs, prefix, &keys, span, &mut batch_bytes);
+            }
+        }
+    }
+    Some(
+        form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(pairs)
+            .finish()
+            .into_bytes(),
+    )
+}
+
+/// JSONプロトコルでのメッセージの本文とメッセージ属性のサイズ
+fn json_message_bytes(message: &Value, keys: &FormKeys) -> usize {
+    message.get(keys.message).and_then(Value::as_str).map_or(0, str::len)
+        + message.get("MessageAttributes").map_or(0, |v| v.to_string().len())
+}
+
+/// JSONプロトコルでのメッセージの `MessageAttributes` に `_datadog` を追加する。
+/// `total_bytes` は上限の判定に使うサイズで、追加した属性のサイズを加算する
+fn inject_json_attribute(message: &mut Value, span: &Span, total_bytes: &mut usize) -> Option<()> {
+    let attribute_count = message
+        .get("MessageAttributes")
+        .and_then(Value::as_object)
+        .map_or(0, |attributes| attributes.len());
+    let value = propagation::sqs_sns_attribute(span, *total_bytes, attribute_count)?;
+    *total_bytes += propagation::sqs_sns_attribute_bytes(&value);
+    message
+        .as_object_mut()?
+        .entry("MessageAttributes")
+        .or_insert_with(|| Value::Object(Default::default()))
+        .as_object_mut()?
+        .insert(
+            propagation::DATADOG_KEY.to_string(),
+            serde_json::json!({ "DataType": "String", "StringValue": value }),
+        );
+    Some(())
+}
+
+/// Queryプロトコルでのメッセージの本文とメッセージ属性のサイズ。`prefix` はバッチのエントリの接頭辞(単一のメッセージなら空)
+fn form_message_bytes(pairs: &[(String, String)], prefix: &str, keys: &FormKeys) -> usize {
+    let message = format!("{}{}", prefix, keys.message);
+    let attribute_prefix = format!("{}{}.", prefix, keys.attribute_prefix);
+    pairs
+        .iter()
+        .filter(|(k, _)| *k == message || k.starts_with(&attribute_prefix))
+        .map(|(_, v)| v.len())
+        .sum()
+}
+
+/// Queryプロトコルでのメッセージに `_datadog` メッセージ属性を追加する。
+/// `total_bytes` は上限の判定に使うサイズで、追加した属性のサイズを加算する
+fn inject_form_attribute(
+    pairs: &mut Vec<(String, String)>, prefix: &str, keys: &FormKeys, span: &Span, total_bytes: &mut usize,
+) -> Option<()> {
+    let attribute_prefix = format!("{}{}.", prefix, keys.attribute_prefix);
+    let attribute_count = pairs
+        .iter()
+        .filter(|(k, _)| k.starts_with(&attribute_prefix) && k.ends_with(".Name"))
+        .count();
+    let value = propagation::sqs_sns_attribute(span, *total_bytes, attribute_count)?;
+    *total_bytes += propagation::sqs_sns_attribute_bytes(&value);
+    let n = attribute_count + 1;
+    pairs.push((
+        format!("{}{}.Name", attribute_prefix, n),
+        propagation::DATADOG_KEY.to_string(),
+    ));
+    pairs.push((
+        format!("{}{}.Value.DataType", attribute_prefix, n),
+        "String".to_string(),
+    ));
+    pairs.push((format!("{}{}.Value.StringValue", attribute_prefix, n), value));
+    Some(())
+}
+
+/// Queryプロトコルでのバッチの各エントリのキーの接頭辞(`{prefix}.{N}.`)を、エントリの番号順に返す
+fn form_entry_prefixes(pairs: &[(String, String)], entry_prefix: &str) -> Vec<String> {
+    let mut indexes: Vec<usize> = pairs
+        .iter()
+        .filter_map(|(k, _)| {
+            k.strip_prefix(entry_prefix)?
+                .strip_prefix('.')?
+                .split('.')
+                .next()?
+                .parse()
+                .ok()
+        })
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes
+        .into_iter()
+        .map(|n| format!("{}.{}.", entry_prefix, n))
+        .collect()
+}
+
+/// EventBridgeの各エントリの `Detail` に `_datadog` を追加する
+fn inject_eventbridge(body: &[u8], span: &Span) -> Option<Vec<u8>> {
+    let mut json: Value = serde_json::from_slice(body).ok()?;
+    for entry in json.get_mut("Entries")?.as_array_mut()? {
+        let Some(detail) = entry.get("Detail").and_then(Value::as_str).map(str::to_string) else {
+            continue;
+        };
+        let other_entry_bytes = entry.to_string().len().saturating_sub(detail.len());
+        if let Some(injected) = propagation::inject_eventbridge_detail(&detail, span, other_entry_bytes) {
+            entry["Detail"] = Value::String(injected);
+        }
+    }
+    serde_json::to_vec(&json).ok()
+}
+
+/// Kinesisの各レコードのデータ(base64)に `_datadog` を追加する
+fn inject_kinesis(body: &[u8], span: &Span) -> Option<Vec<u8>> {
+    let mut json: Value = serde_json::from_slice(body).ok()?;
+    let inject = |record: &mut Value| {
+        let Some(data) = record.get("Data").and_then(Value::as_str) else {
+            return;
+        };
+        let Ok(decoded) = BASE64.decode(data) else {
+            return;
+        };
+        if let Some(injected) = propagation::inject_kinesis_data(&decoded, span) {
+            record["Data"] = Value::String(BASE64.encode(injected));
+        }
+    };
+    match json.get_mut("Records").and_then(Value::as_array_mut) {
+        Some(records) => records.iter_mut().for_each(inject),
+        None => inject(&mut json),
+    }
+    serde_json::to_vec(&json).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::propagation::MAX_MESSAGE_ATTRIBUTES;
+    use serde_json::json;
+
+    fn owned(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn form<K: AsRef<str>, V: AsRef<str>>(pairs: &[(K, V)]) -> Vec<u8> {
+        form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(pairs)
+            .finish()
+            .into_bytes()
+    }
+
+    /// `{prefix}MessageAttribute.1.Name` ... のメッセージ属性を `count` 個作る
+    fn form_attributes(prefix: &str, attribute_prefix: &str, count: usize) -> Vec<(String, String)> {
+        (1..=count)
+            .flat_map(|n| {
+                [
+                    (
+                        format!("{}{}.{}.Name", prefix, attribute_prefix, n),
+                        format!("attr{}", n),
+                    ),
+                    (
+                        format!("{}{}.{}.Value.DataType", prefix, attribute_prefix, n),
+                        "String".to_string(),
+                    ),
+                    (
+                        format!("{}{}.{}.Value.StringValue", prefix, attribute_prefix, n),
+                        "v".to_string(),
+                    ),
+                ]
+            })
+            .collect()
+    }
+
+    fn json_attributes(count: usize) -> Value {
+        (1..=count)
+            .map(|n| {
+                (
+                    format!("attr{}", n),
+                    json!({ "DataType": "String", "StringValue": "v" }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    #[test]
+    fn injects_sqs_attribute_into_query_request() {
+        let body = form(&[("Action", "SendMessage"), ("MessageBody", "hello")]);
+        let injected = inject_message_attribute(&body, &Span::none(), SQS_FORM_KEYS, None).unwrap();
+        assert_eq!(
+            form_field(&injected, "MessageAttribute.1.Name").as_deref(),
+            Some("_datadog")
+        );
+        assert_eq!(
+            form_field(&injected, "MessageAttribute.1.Value.DataType").as_deref(),
+            Some("String")
+        );
+        let value = form_field(&injected, "MessageAttribute.1.Value.StringValue").unwrap();
+        assert!(serde_json::from_str::<Value>(&value).unwrap().is_object());
+        assert_eq!(form_field(&injected, "MessageBody").as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn injects_sns_attribute_after_existing_attributes() {
+        let mut pairs = owned(&[("Action", "Publish"), ("Message", "hello")]);
+        pairs.extend(form_attributes("", "MessageAttributes.entry", 2));
+        let body = form(&pairs);
+        let injected = inject_message_attribute(&body, &Span::none(), SNS_FORM_KEYS, None).unwrap();
+        assert_eq!(
+            form_field(&injected, "MessageAttributes.entry.2.Name").as_deref(),
+            Some("attr2")
+        );
+        assert_eq!(
+            form_field(&injected, "MessageAttributes.entry.3.Name").as_deref(),
+            Some("_datadog")
+        );
+    }
+
+    #[test]
+    fn skips_message_with_max_attributes() {
+        let mut pairs = owned(&[("MessageBody", "hello")]);
+        pairs.extend(form_attributes("", "MessageAttribute", MAX_MESSAGE_ATTRIBUTES));
+        let body = form(&pairs);
+        assert!(inject_message_attribute(&body, &Span::none(), SQS_FORM_KEYS, None).is_none());
+
+        let attributes = json_attributes(MAX_MESSAGE_ATTRIBUTES);
+        let body = json!({ "MessageBody": "hello", "MessageAttributes": attributes }).to_string();
+        assert!(inject_message_attribute(body.as_bytes(), &Span::none(), SQS_FORM_KEYS, None).is_none());
+    }
+
+    #[test]
+    fn injects_sqs_attribute_into_json_request() {
+        let body = json!({ "QueueUrl": "https://sqs/123/queue", "MessageBody": "hello" }).to_string();
+        let injected = inject_message_attribute(body.as_bytes(), &Span::none(), SQS_FORM_KEYS, None).unwrap();
+        let json: Value = serde_json::from_slice(&injected).unwrap();
+        assert_eq!(json["MessageAttributes"]["_datadog"]["DataType"], "String");
+        assert_eq!(json["MessageBody"], "hello");
+    }
+
+    #[test]
+    fn skips_message_exceeding_max_size() {
+        let message = "a".repeat(propagation::SQS_SNS_MAX_MESSAGE_BYTES);
+        let body = json!({ "MessageBody": message }).to_string();
+        assert!(inject_message_attribute(body.as_bytes(), &Span::none(), SQS_FORM_KEYS, None).is_none());
+    }
+
+    #[test]
+    fn injects_each_entry_of_sqs_batch() {
+        let attributes = json_attributes(MAX_MESSAGE_ATTRIBUTES);
+        let body = json!({
+            "Entries": [
+                { "Id": "1", "MessageBody": "a" },
+                { "Id": "2", "MessageBody": "b", "MessageAttributes": attributes },
+            ]
+        })
+        .to_string();
+        let injected =
+            inject_message_attribute(body.as_bytes(), &Span::none(), SQS_FORM_KEYS, Some(SQS_BATCH_KEYS)).unwrap();
+        let json: Value = serde_json::from_slice(&injected).unwrap();
+        assert!(json["Entries"][0]["MessageAttributes"]["_datadog"].is_object());
+        assert!(json["Entries"][1]["MessageAttributes"].get("_datadog").is_none());
+
+        let mut pairs = owned(&[
+            ("SendMessageBatchRequestEntry.1.Id", "1"),
+            ("SendMessageBatchRequestEntry.1.MessageBody", "a"),
+            ("SendMessageBatchRequestEntry.2.Id", "2"),
+            ("SendMessageBatchRequestEntry.2.MessageBody", "b"),
+        ]);
+        pairs.extend(form_attributes(
+            "SendMessageBatchRequestEntry.2.",
+            "MessageAttribute",
+            1,
+        ));
+        let body = form(&pairs);
+        let injected = inject_message_attribute(&body, &Span::none(), SQS_FORM_KEYS, Some(SQS_BATCH_KEYS)).unwrap();
+        assert_eq!(
+            form_field(&injected, "SendMessageBatchRequestEntry.1.MessageAttribute.1.Name").as_deref(),
+            Some("_datadog")
+        );
+        assert_eq!(
+            form_field(&injected, "SendMessageBatchRequestEntry.2.MessageAttribute.2.Name").as_deref(),
+            Some("_datadog")
+        );
+    }
+
+    #[test]
+    fn injects_each_entry_of_sns_batch() {
+        let body = form(&[
+            ("Action", "PublishBatch"),
+            ("PublishBatchRequestEntries.member.1.Id", "1"),
+            ("PublishBatchRequestEntries.member.1.Message", "a"),
+            ("PublishBatchRequestEntries.member.2.Id", "2"),
+            ("PublishBatchRequestEntries.member.2.Message", "b"),
+        ]);
+        let injected = inject_message_attribute(&body, &Span::none(), SNS_FORM_KEYS, Some(SNS_BATCH_KEYS)).unwrap();
+        for n in 1..=2 {
+            let key = format!("PublishBatchRequestEntries.member.{}.MessageAttributes.entry.1.Name", n);
+            assert_eq!(form_field(&injected, &key).as_deref(), Some("_datadog"));
+        }
+    }
+
+    #[test]
+    fn skips_batch_entries_exceeding_total_size() {
+        // 各エントリは上限以下だが、バッチ全体では上限ぎりぎりなので、どちらに埋め込んでも上限を超える
+        let half = "a".repeat(propagation::SQS_SNS_MAX_MESSAGE_BYTES / 2 - 1);
+        let body = json!({ "Entries": [{ "MessageBody": half }, { "MessageBody": half }] }).to_string();
+        let injected =
+            inject_message_attribute(body.as_bytes(), &Span::none(), SQS_FORM_KEYS, Some(SQS_BATCH_KEYS)).unwrap();
+        let json: Value = serde_json::from_slice(&injected).unwrap();
+        for entry in json["Entries"].as_array().unwrap() {
+            assert!(entry.get("MessageAttributes").is_none());
+        }
+    }
+
+    #[test]
+    fn injects_eventbridge_detail_of_each_entry() {
+        let large = json!({ "a": "a".repeat(propagation::EVENTBRIDGE_MAX_ENTRY_BYTES) }).to_string();
+        let body = json!({
+            "Entries": [
+                { "Source": "app", "DetailType": "created", "Detail": "{\"id\":1}" },
+                { "Source": "app", "DetailType": "created", "Detail": "[1,2]" },
+                { "Source": "app", "DetailType": "created", "Detail": large },
+            ]
+        })
+        .to_string();
+        let original: Value = serde_json::from_str(&body).unwrap();
+        let injected: Value =
+            serde_json::from_slice(&inject_eventbridge(body.as_bytes(), &Span::none()).unwrap()).unwrap();
+
+        let detail: Value = serde_json::from_str(injected["Entries"][0]["Detail"].as_str().unwrap()).unwrap();
+        assert_eq!(detail["id"], 1);
+        assert!(detail["_datadog"].is_object());
+        // JSONオブジェクトでないもの、上限を超えるものはそのまま
+        assert_eq!(injected["Entries"][1], original["Entries"][1]);
+        assert_eq!(injected["Entries"][2], original["Entries"][2]);
+    }
+
+    #[test]
+    fn injects_kinesis_data_only_into_json_objects() {
+        let object = BASE64.encode(br#"{"id":1}"#);
+        let array = BASE64.encode(b"[1,2]");
+        let text = BASE64.encode(b"plain text");
+        let body = json!({
+            "StreamName": "stream",
+            "Records": [{ "Data": object }, { "Data": array }, { "Data": text }, { "Data": "not base64!" }]
+        })
+        .to_string();
+        let injected: Value = serde_json::from_slice(&inject_kinesis(body.as_bytes(), &Span::none()).unwrap()).unwrap();
+        let records = injected["Records"].as_array().unwrap();
+
+        let data: Value =
+            serde_json::from_slice(&BASE64.decode(records[0]["Data"].as_str().unwrap()).unwrap()).unwrap();
+        assert_eq!(data["id"], 1);
+        assert!(data["_datadog"].is_object());
+        assert_eq!(records[1]["Data"], array);
+        assert_eq!(records[2]["Data"], text);
+        assert_eq!(records[3]["Data"], "not base64!");
+    }
+
+    #[test]
+    fn injects_kinesis_data_of_put_record() {
+        let body = json!({ "StreamName": "stream", "Data": BASE64.encode(br#"{"id":1}"#) }).to_string();
+        let injected: Value = serde_json::from_slice(&inject_kinesis(body.as_bytes(), &Span::none()).unwrap()).unwrap();
+        let data: Value = serde_json::from_slice(&BASE64.decode(injected["Data"].as_str().unwrap()).unwrap()).unwrap();
+        assert!(data["_datadog"].is_object());
+    }
+}
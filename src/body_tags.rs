@@ -0,0 +1,385 @@
+//! リクエスト・レスポンスのJSONボディのSpanのタグへの展開。
+//!
+//! 決済処理などのデバッグ用に、API GatewayのリクエストのJSONボディを `http.request.body.<パス>` のタグとしてRootSpanにセットする。
+//! レスポンスのボディは、エラーとした場合のみ `http.response.body.<パス>` のタグとしてセットする。
+//! ただしハンドラが返したボディがバッファ済み(`lambda_http::Body` / `String` / `Vec<u8>`)の場合のみで、
+//! axumなどのストリーミングのボディは読むと消費してしまうので対象外。
+//! 配列の要素はインデックスをパスにする(`http.request.body.items.0.price` など)。
+//!
+//! 以下の環境変数で制御する。いずれもデフォルトは無効。
+//! - `DD_TRACE_REQUEST_BODY_TAGS`: リクエストのボディを展開するかどうか
+//! - `DD_TRACE_RESPONSE_BODY_TAGS`: エラー時にレスポンスのボディを展開するかどうか
+//! - `DD_TRACE_BODY_TAGS_MAX_DEPTH`: 展開するネストの深さ(デフォルト10)。それより深い値はタグにしない
+//! - `DD_TRACE_BODY_TAGS_MAX_BYTES`: 展開するボディの最大サイズ(デフォルト16KiB)。超える場合は展開しない
+//! - `DD_TRACE_BODY_TAGS_REDACTION`: 値を `<redacted>` に置き換えるJSONPathのカンマ区切り
+//!
+//! `DD_TRACE_BODY_TAGS_REDACTION` の指定に関わらず、キー名に `token` / `email` / `userid` / `password` / `secret` を含む値は常に秘匿する。
+//! 比較は大文字小文字と `_` / `-` を無視して行うので、`access_token` / `Email` / `customer_email` / `userId` / `user-id` なども対象になる。
+//! オブジェクトや配列が秘匿対象にマッチした場合は、その配下全体を1つのタグとして秘匿する。
+//! JSONPathは `$.a.b` / `$.a[0]` / `$.a[*].b` / `$['a']` / `$..a`(再帰) / `*`(ワイルドカード) のみ対応する。
+//! JSONPathの名前も大文字小文字と `_` / `-` を無視して比較する(`$..cardNumber` は `card_number` にもマッチする)。
+
+use crate::config::CONFIG;
+use crate::helper;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::any::Any;
+use tracing::{warn, Span};
+
+const REDACTED: &str = "<redacted>";
+/// キー名(正規化後)にこれらを含む値は常に秘匿する
+const SENSITIVE_KEYS: [&str; 5] = ["token", "email", "userid", "password", "secret"];
+/// 1つのボディから作成するタグの上限数
+const MAX_TAGS: usize = 256;
+
+static REDACTION: Lazy<Vec<Vec<Selector>>> = Lazy::new(|| {
+    CONFIG
+        .body_tags_redaction
+        .iter()
+        .filter_map(|path| {
+            let selectors = parse_json_path(path);
+            if selectors.is_none() {
+                warn!(path, "invalid JSONPath in DD_TRACE_BODY_TAGS_REDACTION");
+            }
+            selectors
+        })
+        .collect()
+});
+
+/// JSONPathの要素。名前・インデックスがNoneの場合はワイルドカード
+#[derive(Debug)]
+enum Selector {
+    Child(Option<String>),
+    Descendant(Option<String>),
+    Index(Option<usize>),
+}
+
+/// JSONの値の位置を表すパスの要素
+#[derive(Debug, Clone, Copy)]
+enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+impl Segment<'_> {
+    fn matches(&self, selector: &Selector) -> bool {
+        match (selector, self) {
+            (Selector::Child(None) | Selector::Descendant(None) | Selector::Index(None), _) => true,
+            (Selector::Child(Some(name)) | Selector::Descendant(Some(name)), Segment::Key(key)) => {
+                *name == normalize_key(key)
+            }
+            (Selector::Index(Some(i)), Segment::Index(j)) => i == j,
+            _ => false,
+        }
+    }
+}
+
+/// リクエストのボディをタグとしてSpanにセットする
+pub(crate) fn set_request_body_tags(span: &Span, body: &[u8]) {
+    if CONFIG.request_body_tags {
+        set_tags(span, "http.request.body", body);
+    }
+}
+
+/// レスポンスのボディをタグとしてSpanにセットする。エラーとした場合のみ呼ぶ。
+/// ボディがバッファ済みでない(None)場合は何もしない
+pub(crate) fn set_response_body_tags(span: &Span, body: Option<&[u8]>) {
+    if let (true, Some(body)) = (CONFIG.response_body_tags, body) {
+        set_tags(span, "http.response.body", body);
+    }
+}
+
+/// レスポンスのボディがバッファ済み(`lambda_http::Body` / `String` / `Vec<u8>`)なら、そのバイト列を返す。
+/// ハンドラのボディの型に制約を付けないように、型で判別する
+pub(crate) fn buffered_body<B: 'static>(body: &B) -> Option<&[u8]> {
+    let body = body as &dyn Any;
+    if let Some(body) = body.downcast_ref::<lambda_http::Body>() {
+        Some(body.as_ref())
+    } else if let Some(body) = body.downcast_ref::<String>() {
+        Some(body.as_bytes())
+    } else {
+        body.downcast_ref::<Vec<u8>>().map(Vec::as_slice)
+    }
+}
+
+fn set_tags(span: &Span, prefix: &str, body: &[u8]) {
+    if body.is_empty() || body.len() > CONFIG.body_tags_max_bytes {
+        return;
+    }
+    // JSONでなければ展開しない
+    let Ok(value) = serde_json::from_slice::<Value>(body) else {
+        return;
+    };
+    for (path, value) in expand(&value, CONFIG.body_tags_max_depth, &REDACTION) {
+        helper::set_tag(span, &format!("{}.{}", prefix, path), &value);
+    }
+}
+
+/// JSONの値を `(パス, 値)` のリストに展開する
+fn expand(value: &Value, max_depth: usize, redaction: &[Vec<Selector>]) -> Vec<(String, String)> {
+    let mut tags = vec![];
+    flatten(value, &mut vec![], &mut tags, max_depth, redaction);
+    tags
+}
+
+fn flatten<'a>(
+    value: &'a Value, path: &mut Vec<Segment<'a>>, tags: &mut Vec<(String, String)>, max_depth: usize,
+    redaction: &[Vec<Selector>],
+) {
+    if tags.len() >= MAX_TAGS {
+        return;
+    }
+    if !path.is_empty() && is_redacted(path, redaction) {
+        tags.push((join(path), REDACTED.to_string()));
+        return;
+    }
+    match value {
+        Value::Object(map) if path.len() < max_depth => {
+            for (key, child) in map {
+                path.push(Segment::Key(key));
+                flatten(child, path, tags, max_depth, redaction);
+                path.pop();
+            }
+        }
+        Value::Array(array) if path.len() < max_depth => {
+            for (i, child) in array.iter().enumerate() {
+                path.push(Segment::Index(i));
+                flatten(child, path, tags, max_depth, redaction);
+                path.pop();
+            }
+        }
+        // 上限より深いものは、秘匿対象が含まれうるのでそのまま出さずに捨てる
+        Value::Object(_) | Value::Array(_) => {}
+        Value::String(s) if !path.is_empty() => tags.push((join(path), s.clone())),
+        _ if !path.is_empty() => tags.push((join(path), value.to_string())),
+        _ => {}
+    }
+}
+
+/// 末尾のキー名が秘匿対象のキーを含むか、JSONPathのいずれかにマッチすれば秘匿する
+fn is_redacted(path: &[Segment], redaction: &[Vec<Selector>]) -> bool {
+    let sensitive_key = match path.last() {
+        Some(Segment::Key(key)) => {
+            let key = normalize_key(key);
+            SENSITIVE_KEYS.iter().any(|k| key.contains(k))
+        }
+        _ => false,
+    };
+    sensitive_key || redaction.iter().any(|selectors| matches(selectors, path))
+}
+
+/// キー名を比較用に正規化する。大文字小文字と `_` / `-` を無視する
+fn normalize_key(key: &str) -> String {
+    key.chars()
+        .filter(|c| *c != '_' && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn join(path: &[Segment]) -> String {
+    let segments: Vec<String> = path
+        .iter()
+        .map(|s| match s {
+            Segment::Key(key) => key.to_string(),
+            Segment::Index(i) => i.to_string(),
+        })
+        .collect();
+    segments.join(".")
+}
+
+/// JSONPathがパスにマッチするか
+fn matches(selectors: &[Selector], path: &[Segment]) -> bool {
+    match selectors.split_first() {
+        None => path.is_empty(),
+        Some((selector @ Selector::Descendant(_), rest)) => {
+            (0..path.len()).any(|i| path[i].matches(selector) && matches(rest, &path[i + 1..]))
+        }
+        Some((selector, rest)) => match path.split_first() {
+            Some((segment, remaining)) => segment.matches(selector) && matches(rest, remaining),
+            None => false,
+        },
+    }
+}
+
+/// JSONPathを解析する。対応していない形式の場合はNone
+fn parse_json_path(path: &str) -> Option<Vec<Selector>> {
+    let mut rest = path.trim().strip_prefix('$')?;
+    let mut selectors = vec![];
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("..") {
+            let (name, r) = take_name(r)?;
+            selectors.push(Selector::Descendant(name));
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('.') {
+            let (name, r) = take_name(r)?;
+            selectors.push(Selector::Child(name));
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let (inner, r) = r.split_once(']')?;
+            let inner = inner.trim();
+            let selector = if inner == "*" {
+                Selector::Index(None)
+            } else if let Some(name) = inner.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) {
+                Selector::Child(Some(normalize_key(name)))
+            } else {
+                Selector::Index(Some(inner.parse().ok()?))
+            };
+            selectors.push(selector);
+            rest = r;
+        } else {
+            return None;
+        }
+    }
+    Some(selectors)
+}
+
+/// `.` の後の名前を取り出す。`*` ならNone
+fn take_name(path: &str) -> Option<(Option<String>, &str)> {
+    let end = path.find(|c| c == '.' || c == '[').unwrap_or(path.len());
+    let (name, rest) = path.split_at(end);
+    match name {
+        "" => None,
+        "*" => Some((None, rest)),
+        _ => Some((Some(normalize_key(name)), rest)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn paths(paths: &[&str]) -> Vec<Vec<Selector>> {
+        paths.iter().map(|p| parse_json_path(p).unwrap()).collect()
+    }
+
+    fn tag<'a>(tags: &'a [(String, String)], key: &str) -> Option<&'a str> {
+        tags.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn expands_nested_objects_and_arrays() {
+        let tags = expand(
+            &json!({ "amount": 100, "items": [{ "name": "apple" }], "ok": true }),
+            10,
+            &[],
+        );
+        assert_eq!(tag(&tags, "amount"), Some("100"));
+        assert_eq!(tag(&tags, "items.0.name"), Some("apple"));
+        assert_eq!(tag(&tags, "ok"), Some("true"));
+    }
+
+    #[test]
+    fn always_redacts_sensitive_keys_regardless_of_case_and_separator() {
+        let value = json!({
+            "userId": "u1",
+            "Email": "a@example.com",
+            "access_token": "t",
+            "customer": { "customer_email": "b@example.com", "user-id": "u2" },
+            "Password": "p",
+            "amount": 100,
+        });
+        let tags = expand(&value, 10, &[]);
+        for key in [
+            "userId",
+            "Email",
+            "access_token",
+            "customer.customer_email",
+            "customer.user-id",
+            "Password",
+        ] {
+            assert_eq!(tag(&tags, key), Some(REDACTED), "{}", key);
+        }
+        assert_eq!(tag(&tags, "amount"), Some("100"));
+    }
+
+    #[test]
+    fn redacts_whole_subtree_of_sensitive_key() {
+        let tags = expand(&json!({ "tokens": { "a": "x", "b": "y" } }), 10, &[]);
+        assert_eq!(tags, vec![("tokens".to_string(), REDACTED.to_string())]);
+    }
+
+    #[test]
+    fn recursive_descent() {
+        let tags = expand(
+            &json!({ "card": { "number": "4111" }, "a": { "b": { "number": "1" } } }),
+            10,
+            &paths(&["$..number"]),
+        );
+        assert_eq!(tag(&tags, "card.number"), Some(REDACTED));
+        assert_eq!(tag(&tags, "a.b.number"), Some(REDACTED));
+    }
+
+    #[test]
+    fn child_path_matches_only_that_position() {
+        let tags = expand(
+            &json!({ "card": { "number": "4111" }, "number": "1" }),
+            10,
+            &paths(&["$.card.number"]),
+        );
+        assert_eq!(tag(&tags, "card.number"), Some(REDACTED));
+        assert_eq!(tag(&tags, "number"), Some("1"));
+    }
+
+    #[test]
+    fn wildcard_index() {
+        let value = json!({ "cards": [{ "cvc": "123", "brand": "visa" }, { "cvc": "456", "brand": "amex" }] });
+        let tags = expand(&value, 10, &paths(&["$.cards[*].cvc"]));
+        assert_eq!(tag(&tags, "cards.0.cvc"), Some(REDACTED));
+        assert_eq!(tag(&tags, "cards.1.cvc"), Some(REDACTED));
+        assert_eq!(tag(&tags, "cards.0.brand"), Some("visa"));
+    }
+
+    #[test]
+    fn specific_index() {
+        let tags = expand(&json!({ "cards": ["a", "b"] }), 10, &paths(&["$.cards[1]"]));
+        assert_eq!(tag(&tags, "cards.0"), Some("a"));
+        assert_eq!(tag(&tags, "cards.1"), Some(REDACTED));
+    }
+
+    #[test]
+    fn bracket_name() {
+        let tags = expand(
+            &json!({ "card": { "number": "4111" } }),
+            10,
+            &paths(&["$['card']['number']"]),
+        );
+        assert_eq!(tag(&tags, "card.number"), Some(REDACTED));
+    }
+
+    #[test]
+    fn path_names_ignore_case_and_separator() {
+        let tags = expand(&json!({ "card_number": "4111" }), 10, &paths(&["$..cardNumber"]));
+        assert_eq!(tag(&tags, "card_number"), Some(REDACTED));
+    }
+
+    #[test]
+    fn drops_values_deeper_than_max_depth() {
+        let tags = expand(&json!({ "a": { "b": { "c": "deep" } }, "x": "shallow" }), 2, &[]);
+        assert_eq!(tag(&tags, "x"), Some("shallow"));
+        assert!(tags.iter().all(|(k, _)| !k.starts_with("a.")));
+    }
+
+    #[test]
+    fn limits_number_of_tags() {
+        let value = Value::Array((0..MAX_TAGS + 10).map(Value::from).collect());
+        let tags = expand(&json!({ "list": value }), 10, &[]);
+        assert_eq!(tags.len(), MAX_TAGS);
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        for path in ["", "card.number", "$.", "$..", "$[abc]", "$[1", "$.a..", "$a"] {
+            assert!(parse_json_path(path).is_none(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn parses_supported_paths() {
+        for path in [
+            "$", "$.a", "$.a.b", "$..a", "$.*", "$.a[*]", "$.a[0].b", "$['a']", "$..*",
+        ] {
+            assert!(parse_json_path(path).is_some(), "{}", path);
+        }
+    }
+}
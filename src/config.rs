@@ -0,0 +1,221 @@
+//! 環境変数から読み込む設定値。
+//! Datadog公式のライブラリと同じ名前の環境変数があるものはそれに合わせている。
+
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::env;
+use std::ops::RangeInclusive;
+use tracing::warn;
+
+/// `DD_TRACE_OBFUSCATION_QUERY_STRING_REGEXP` のデフォルト値。Datadog公式のライブラリと同じもの
+const DEFAULT_OBFUSCATION_QUERY_STRING_REGEXP: &str = r#"(?i)(?:p(?:ass)?w(?:or)?d|pass(?:_?phrase)?|secret|(?:api_?|private_?|public_?|access_?|secret_?)key(?:_?id)?|token|consumer_?(?:id|key|secret)|sign(?:ed|ature)?|auth(?:entication|orization)?)(?:(?:\s|%20)*(?:=|%3D)[^&]+|(?:"|%22)(?:\s|%20)*(?::|%3A)(?:\s|%20)*(?:"|%22)(?:%2[^2]|%[^2]|[^"%])+(?:"|%22))|bearer(?:\s|%20)+[a-z0-9\._\-]+|token(?::|%3A)[a-z0-9]{13}|gh[opsu]_[0-9a-zA-Z]{36}|ey[I-L](?:[\w=-]|%3D)+\.ey[I-L](?:[\w=-]|%3D)+(?:\.(?:[\w.+\/=-]|%3D|%2F|%2B)+)?|[\-]{5}BEGIN(?:[a-z\s]|%20)+PRIVATE(?:\s|%20)KEY[\-]{5}[^\-]+[\-]{5}END(?:[a-z\s]|%20)+PRIVATE(?:\s|%20)KEY|ssh-rsa(?:\s|%20)*(?:[a-z0-9\/\.+]|%2F|%5C|%2B){100,}"#;
+
+pub(crate) static CONFIG: Lazy<Config> = Lazy::new(Config::from_env);
+
+#[derive(Debug)]
+pub(crate) struct Config {
+    /// 呼び出し元(API GatewayやDynamoDB Streamsなど)の推論Spanを作成するかどうか。`DD_TRACE_MANAGED_SERVICES`
+    pub managed_services: bool,
+    /// タイムアウトの何ミリ秒前にRootSpanをエラーにしてフラッシュするか。`DD_APM_FLUSH_DEADLINE_MILLISECONDS`
+    pub flush_deadline_margin_ms: u64,
+    /// タイムアウト直前にハンドラの処理を打ち切るかどうか。`DD_CANCEL_ON_IMPENDING_TIMEOUT`
+    pub cancel_on_impending_timeout: bool,
+    /// 拡張メトリクス(`aws.lambda.enhanced.*`)を送るかどうか。`DD_ENHANCED_METRICS`。
+    /// `out_of_memory` と、タイムアウト直前を検知できなかった場合の `timeouts` は `DD_TELEMETRY_API=true` の場合のみ送られる
+    pub enhanced_metrics: bool,
+    /// DogStatsDの送信先ホスト。`DD_DOGSTATSD_HOST`
+    pub dogstatsd_host: String,
+    /// DogStatsDの送信先ポート。`DD_DOGSTATSD_PORT`
+    pub dogstatsd_port: u16,
+    /// DogStatsDのUnixドメインソケットのパス。指定された場合はUDPより優先する。`DD_DOGSTATSD_SOCKET`
+    pub dogstatsd_socket: Option<String>,
+    /// Datadog Lambda Extensionの有無に関わらず、カスタムメトリクスをログに出力するかどうか。`DD_FLUSH_TO_LOG`
+    pub flush_to_log: bool,
+    /// Datadog Lambda ExtensionのURL。指定された場合はExtensionが有るものとして扱う。`DD_EXTENSION_URL`
+    pub extension_url: Option<String>,
+    /// Datadog Lambda Extensionのuniversal instrumentationを使うかどうか。`DD_UNIVERSAL_INSTRUMENTATION`
+    pub universal_instrumentation: bool,
+    /// 内部Extensionとして登録し、実行環境のShutdown時に送信待ちのSpanとメトリクスを送り切るかどうか。`DD_SHUTDOWN_FLUSH`
+    pub shutdown_flush: bool,
+    /// Lambda Telemetry APIを購読して、プラットフォームの計測値をRootSpanにセットするかどうか。`DD_TELEMETRY_API`
+    pub telemetry_api: bool,
+    /// Telemetry APIからレコードを受け取るポート。`DD_TELEMETRY_LISTENER_PORT`
+    pub telemetry_listener_port: u16,
+    /// クエリ文字列の秘匿対象のパターン。Noneなら秘匿しない。`DD_TRACE_OBFUSCATION_QUERY_STRING_REGEXP`
+    pub obfuscation_query_string_regexp: Option<String>,
+    /// `peer.service` の置き換え。`DD_TRACE_PEER_SERVICE_MAPPING` (`元の値:新しい値` のカンマ区切り)
+    pub peer_service_mapping: HashMap<String, String>,
+    /// サーバ側のSpanをエラーとするステータスコード。`DD_TRACE_HTTP_SERVER_ERROR_STATUSES` (デフォルト `500-599`)
+    pub server_error_statuses: Vec<RangeInclusive<u16>>,
+    /// クライアント側のSpanをエラーとするステータスコード。`DD_TRACE_HTTP_CLIENT_ERROR_STATUSES` (デフォルト `500-599`)
+    pub client_error_statuses: Vec<RangeInclusive<u16>>,
+    /// タグにするヘッダ(小文字)とタグ名。`DD_TRACE_HEADER_TAGS` (`ヘッダ名:タグ名` のカンマ区切り、タグ名は省略可)
+    pub header_tags: Vec<(String, Option<String>)>,
+    /// リクエストのJSONボディをタグに展開するかどうか。`DD_TRACE_REQUEST_BODY_TAGS`
+    pub request_body_tags: bool,
+    /// エラー時にレスポンスのJSONボディをタグに展開するかどうか。`DD_TRACE_RESPONSE_BODY_TAGS`
+    pub response_body_tags: bool,
+    /// ボディを展開するネストの深さ。`DD_TRACE_BODY_TAGS_MAX_DEPTH`
+    pub body_tags_max_depth: usize,
+    /// 展開するボディの最大サイズ(バイト)。`DD_TRACE_BODY_TAGS_MAX_BYTES`
+    pub body_tags_max_bytes: usize,
+    /// ボディの値を秘匿するJSONPath(秘匿対象のキー名を含むものは指定に関わらず秘匿する)。`DD_TRACE_BODY_TAGS_REDACTION` (カンマ区切り)
+    pub body_tags_redaction: Vec<String>,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        Config {
+            managed_services: env_bool("DD_TRACE_MANAGED_SERVICES", true),
+            flush_deadline_margin_ms: env_u64("DD_APM_FLUSH_DEADLINE_MILLISECONDS", 100),
+            cancel_on_impending_timeout: env_bool("DD_CANCEL_ON_IMPENDING_TIMEOUT", false),
+            enhanced_metrics: env_bool("DD_ENHANCED_METRICS", true),
+            dogstatsd_host: env::var("DD_DOGSTATSD_HOST").unwrap_or_else(|_| "127.0.0.1".to_string()),
+            dogstatsd_port: env_port("DD_DOGSTATSD_PORT", 8125),
+            dogstatsd_socket: env::var("DD_DOGSTATSD_SOCKET").ok().filter(|v| !v.is_empty()),
+            flush_to_log: env_bool("DD_FLUSH_TO_LOG", false),
+            extension_url: env::var("DD_EXTENSION_URL").ok().filter(|v| !v.is_empty()),
+            universal_instrumentation: env_bool("DD_UNIVERSAL_INSTRUMENTATION", false),
+            shutdown_flush: env_bool("DD_SHUTDOWN_FLUSH", false),
+            telemetry_api: env_bool("DD_TELEMETRY_API", false),
+            telemetry_listener_port: env_port("DD_TELEMETRY_LISTENER_PORT", 4243),
+            // 空文字が指定された場合は秘匿しない
+            obfuscation_query_string_regexp: match env::var("DD_TRACE_OBFUSCATION_QUERY_STRING_REGEXP") {
+                Ok(v) if v.is_empty() => None,
+                Ok(v) => Some(v),
+                Err(_) => Some(DEFAULT_OBFUSCATION_QUERY_STRING_REGEXP.to_string()),
+            },
+            peer_service_mapping: env_map("DD_TRACE_PEER_SERVICE_MAPPING"),
+            server_error_statuses: env_status_ranges("DD_TRACE_HTTP_SERVER_ERROR_STATUSES", "500-599"),
+            client_error_statuses: env_status_ranges("DD_TRACE_HTTP_CLIENT_ERROR_STATUSES", "500-599"),
+            header_tags: env_header_tags("DD_TRACE_HEADER_TAGS"),
+            request_body_tags: env_bool("DD_TRACE_REQUEST_BODY_TAGS", false),
+            response_body_tags: env_bool("DD_TRACE_RESPONSE_BODY_TAGS", false),
+            body_tags_max_depth: env_u64("DD_TRACE_BODY_TAGS_MAX_DEPTH", 10) as usize,
+            body_tags_max_bytes: env_u64("DD_TRACE_BODY_TAGS_MAX_BYTES", 16384) as usize,
+            body_tags_redaction: env::var("DD_TRACE_BODY_TAGS_REDACTION")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+}
+
+fn env_bool(key: &str, default: bool) -> bool {
+    match env::var(key) {
+        Ok(v) => matches!(v.to_lowercase().as_str(), "true" | "1"),
+        Err(_) => default,
+    }
+}
+
+fn env_u64(key: &str, default: u64) -> u64 {
+    env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// ポート番号を読み込む。ポート番号として不正な値(65536以上など)の場合は、警告を出してデフォルト値にする
+fn env_port(key: &str, default: u16) -> u16 {
+    match env::var(key) {
+        Ok(v) => parse_port(key, &v, default),
+        Err(_) => default,
+    }
+}
+
+fn parse_port(key: &str, value: &str, default: u16) -> u16 {
+    value.trim().parse().unwrap_or_else(|_| {
+        warn!("{} is not a valid port: {:?}. using default {}", key, value, default);
+        default
+    })
+}
+
+/// `key1:value1,key2:value2` の形式を読み込む。形式が不正な要素は無視する
+fn env_map(key: &str) -> HashMap<String, String> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| {
+            let (k, v) = pair.split_once(':')?;
+            let (k, v) = (k.trim(), v.trim());
+            (!k.is_empty() && !v.is_empty()).then(|| (k.to_string(), v.to_string()))
+        })
+        .collect()
+}
+
+/// `500-599,429` の形式を読み込む。形式が不正な要素は無視する
+fn env_status_ranges(key: &str, default: &str) -> Vec<RangeInclusive<u16>> {
+    parse_status_ranges(key, &env::var(key).unwrap_or_else(|_| default.to_string()))
+}
+
+/// 始点と終点が逆(`599-500`)の場合は空の範囲になってしまうので、警告を出して入れ替える
+fn parse_status_ranges(key: &str, value: &str) -> Vec<RangeInclusive<u16>> {
+    value
+        .split(',')
+        .filter_map(|item| {
+            let item = item.trim();
+            let (start, end) = item.split_once('-').unwrap_or((item, item));
+            let (start, end): (u16, u16) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+            if start > end {
+                warn!("{} has a reversed range: {:?}. using {}-{}", key, item, end, start);
+                return Some(end..=start);
+            }
+            Some(start..=end)
+        })
+        .collect()
+}
+
+/// `header1:tag1,header2` の形式を読み込む。ヘッダ名は小文字にする
+fn env_header_tags(key: &str) -> Vec<(String, Option<String>)> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|item| {
+            let (header, tag) = match item.split_once(':') {
+                Some((header, tag)) => (header.trim(), Some(tag.trim()).filter(|t| !t.is_empty())),
+                None => (item.trim(), None),
+            };
+            (!header.is_empty()).then(|| (header.to_lowercase(), tag.map(str::to_string)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_port() {
+        assert_eq!(parse_port("PORT", "8126", 8125), 8126);
+        assert_eq!(parse_port("PORT", " 65535 ", 8125), 65535);
+    }
+
+    #[test]
+    fn falls_back_to_default_port_instead_of_truncating() {
+        assert_eq!(parse_port("PORT", "65536", 8125), 8125);
+        assert_eq!(parse_port("PORT", "-1", 8125), 8125);
+        assert_eq!(parse_port("PORT", "port", 8125), 8125);
+    }
+
+    #[test]
+    fn parses_status_ranges_and_single_values() {
+        assert_eq!(
+            parse_status_ranges("STATUSES", "500-599, 429 ,404-404"),
+            vec![500..=599, 429..=429, 404..=404]
+        );
+    }
+
+    #[test]
+    fn normalizes_reversed_status_range() {
+        assert_eq!(parse_status_ranges("STATUSES", "599-500"), vec![500..=599]);
+    }
+
+    #[test]
+    fn ignores_invalid_status_ranges() {
+        assert_eq!(
+            parse_status_ranges("STATUSES", "5xx,500-,-599,70000,,429"),
+            vec![429..=429]
+        );
+        assert!(parse_status_ranges("STATUSES", "").is_empty());
+    }
+}
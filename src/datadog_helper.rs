@@ -1,28 +1,52 @@
+use crate::body_tags;
+use crate::config::CONFIG;
+use crate::dd_extension;
+use crate::dogstatsd;
+use crate::enhanced_metrics;
+use crate::error_policy;
+use crate::error_policy::ResponseInfo;
+use crate::header_tags;
+use crate::inferred_span::InferredSpan;
+use crate::lambda_meta;
+use crate::lambda_meta::{FunctionMeta, ImpendingTimeout, Invocation};
+use crate::peer_service;
+use crate::telemetry_api;
+use crate::trace_extractor::TraceExtractor;
+use crate::url_sanitizer;
+use aws_lambda_events::event::dynamodb;
+use aws_lambda_events::event::s3::S3Event;
 use chrono::{DateTime, Utc};
 use lambda_http::http::header::CONTENT_TYPE;
 use lambda_http::http::{HeaderMap, HeaderValue};
 use lambda_http::request::RequestContext;
-use lambda_http::{Body, Request, RequestExt};
-use lambda_runtime::Error;
+use lambda_http::{Request, RequestExt};
+use lambda_runtime::{Context as LambdaContext, Error, LambdaEvent};
 use rand::Rng;
 use serde::Serialize;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::future::Future;
+use std::sync::Mutex;
+use tokio::task::JoinHandle;
 use tracing::field::Field;
 use tracing::span::{Attributes, Record};
-use tracing::{info_span, warn, Id};
+use tracing::{info_span, warn, Dispatch, Id, Span};
 use tracing_subscriber::layer::Context;
 use tracing_subscriber::registry::{LookupSpan, SpanRef};
-use tracing_subscriber::Layer;
+use tracing_subscriber::{Layer, Registry};
 
-pub async fn handle_request_with_trace<Fut>(
+pub async fn handle_request_with_trace<B, Fut>(
     req: Request, f: impl FnOnce(Request) -> Fut,
-) -> Result<lambda_http::Response<Body>, Error>
+) -> Result<lambda_http::Response<B>, Error>
 where
-    Fut: Future<Output = Result<lambda_http::Response<Body>, Error>>,
+    Fut: Future<Output = Result<lambda_http::Response<B>, Error>>,
+    B: 'static,
 {
+    if dd_extension::use_universal_instrumentation() {
+        return handle_request_with_universal_instrumentation(req, f).await;
+    }
+    let invocation = Invocation::start();
     // リクエスト元のトレースと繋げる場合、ヘッダでトレーシングID情報が渡されるはずなのでそれを引き継ぐ。無ければ新規採番
     let trace_id = TraceId::from_header(req.headers()).unwrap_or_else(TraceId::new);
     let parent_id = ParentSpanId::from_header(req.headers()).unwrap_or_else(ParentSpanId::new);
@@ -40,80 +64,553 @@ where
     // info!(dd.trace_id = trace_id.0, dd.parent_id = parent_id.0);
 
     let lambda_ctx = req.lambda_context();
-    let request_id = &lambda_ctx.request_id;
     let apigw_ctx = req.request_context();
     let RequestContext::ApiGatewayV1(rest_api_ctx) = apigw_ctx;
+    // API Gatewayの推論Spanを作成する(DD_TRACE_MANAGED_SERVICES=falseなら作成しない)
+    // 作成した場合はRootSpanの親になる
+    let apigw_span = InferredSpan::from_apigw_rest_context(&rest_api_ctx)
+        .filter(|_| CONFIG.managed_services)
+        .map(|info| inferred_span(trace_id, parent_id, &info));
+    let parent_id = apigw_span
+        .as_ref()
+        .and_then(|s| s.id())
+        .map_or(parent_id, |id| ParentSpanId(id.into_u64()));
     let path = rest_api_ctx.path.unwrap();
     // RootSpanを作成する
-    let span = info_span!(
-        "handle_request_root",
-        dd.trace_id = trace_id.0, // ログとトレースのマージのためにどこかでログ内にTraceIdを含めておきたい意図あり
-        dd.parent_id = parent_id.0,
-        // 以下任意でDatadogに渡したい値をセットして下さい。以下は一例です。
-        // 後から `span.record(..)` で更新するケースでも、このタイミングで宣言しておく必要があります。
-        dd.resource = path,
-        dd.error = false,
-        dd.meta.span.kind = "server",
-        dd.meta.request_id = request_id,
-        dd.meta.http.status_code = tracing::field::Empty,
-        dd.meta.error.msg = None::<String>,
+    let span = root_span(
+        trace_id,
+        parent_id,
+        &lambda_ctx,
+        &invocation,
+        &lambda_ctx.env_config.function_name,
+        "server",
     );
+    set_tag(&span, "http.url", &path);
+    header_tags::set_request_tags(&span, req.headers());
+    body_tags::set_request_body_tags(&span, req.body().as_ref());
     let _enter = span.enter();
-    match f(req).await {
+    let parents = apigw_span.iter().collect::<Vec<_>>();
+    let (result, is_error) = match guard_timeout(&span, &parents, &lambda_ctx, Some(&invocation), f(req)).await {
         Ok(ret) => {
             span.record("dd.meta.http.status_code", ret.status().as_u16());
-            if ret.status().as_u16() >= 500 {
+            header_tags::set_response_tags(&span, ret.headers());
+            if let Some(apigw_span) = &apigw_span {
+                set_tag(apigw_span, "http.status_code", &ret.status().as_u16().to_string());
+            }
+            let res = ResponseInfo {
+                status: ret.status().as_u16(),
+                headers: ret.headers(),
+                body: body_tags::buffered_body(ret.body()),
+            };
+            let is_error = error_policy::is_server_error(&res);
+            if is_error {
+                body_tags::set_response_body_tags(&span, res.body);
                 span.record("dd.error", true);
+                if let Some(apigw_span) = &apigw_span {
+                    apigw_span.record("dd.error", true);
+                }
             }
-            Ok(ret)
+            (Ok(ret), is_error)
         }
         Err(err) => {
             span.record("dd.error", true);
             span.record("dd.meta.error.msg", err.to_string());
-            Err(err)
+            (Err(err), true)
         }
+    };
+    enhanced_metrics::submit(&lambda_ctx, &invocation, is_error);
+    dogstatsd::client().flush();
+    // コールドスタートなら、Telemetry APIから届いている初期化時間をセットする
+    for (key, value) in telemetry_api::take_init_metrics(invocation.cold_start) {
+        set_metric(&span, key, value);
     }
+    // Spanを閉じてから、Extensionにフラッシュを依頼する
+    drop(_enter);
+    telemetry_api::defer_root_span(&lambda_ctx, &invocation, &span);
+    drop(span);
+    apigw_span.iter().for_each(finish_inferred_span);
+    drop(apigw_span);
+    flush_to_extension().await;
+    result
 }
 
-/// Reqwestを使ったHTTP処理において、Datadog用のトレース処理を挿入する関数。
-/// 引数(リクエスト)やその他の属性をセットし、また処理結果をトレースに反映する。
-pub async fn request_http(
-    client: &reqwest::Client, mut req: reqwest::Request,
-) -> Result<reqwest::Response, reqwest::Error> {
+/// Datadog Lambda Extensionのuniversal instrumentationを使う場合の [handle_request_with_trace]。
+/// RootSpan(`aws.lambda`)・推論Span・拡張メトリクスはExtensionが作成するので、ここではそのRootSpanの子としてハンドラのSpanを作成する。
+async fn handle_request_with_universal_instrumentation<B, Fut>(
+    req: Request, f: impl FnOnce(Request) -> Fut,
+) -> Result<lambda_http::Response<B>, Error>
+where
+    Fut: Future<Output = Result<lambda_http::Response<B>, Error>>,
+    B: 'static,
+{
+    let lambda_ctx = req.lambda_context();
+    let payload = dd_extension::request_payload(&req);
+    // Extensionに繋がらなかった場合でも、こちらのトレースは送れるようにヘッダから引き継ぐか新規採番する
+    let trace_id = match dd_extension::start_invocation(&lambda_ctx.request_id, &payload).await {
+        Some(tc) => TraceId(tc.trace_id),
+        None => TraceId::from_header(req.headers()).unwrap_or_else(TraceId::new),
+    };
+    TraceId::store(trace_id);
+    // ExtensionのRootSpanのIDはこちらで採番して、end-invocationで渡す
+    let execution_span_id = ParentSpanId::new();
+
     let span = info_span!(
-        "reqwest.http",
-        dd.resource = req.url().to_string(),
+        "aws.lambda.handler",
+        dd.trace_id = trace_id.0,
+        dd.parent_id = execution_span_id.0,
+        dd.resource = lambda_ctx.env_config.function_name.as_str(),
         dd.error = false,
-        dd.meta.span.kind = "client",
-        dd.meta.http.method = req.method().to_string(),
+        dd.meta.span.kind = "internal",
+        dd.meta.request_id = lambda_ctx.request_id.as_str(),
         dd.meta.http.status_code = tracing::field::Empty,
         dd.meta.error.msg = None::<String>,
     );
     let _enter = span.enter();
 
+    header_tags::set_request_tags(&span, req.headers());
+    body_tags::set_request_body_tags(&span, req.body().as_ref());
+    let result = guard_timeout(&span, &[], &lambda_ctx, None, f(req)).await;
+    let (response, error) = match &result {
+        Ok(ret) => {
+            let status = ret.status().as_u16();
+            span.record("dd.meta.http.status_code", status);
+            header_tags::set_response_tags(&span, ret.headers());
+            let res = ResponseInfo {
+                status,
+                headers: ret.headers(),
+                body: body_tags::buffered_body(ret.body()),
+            };
+            let error = error_policy::is_server_error(&res).then(|| format!("http status {}", status));
+            if error.is_some() {
+                body_tags::set_response_body_tags(&span, res.body);
+            }
+            (serde_json::json!({ "statusCode": status }), error)
+        }
+        Err(err) => (serde_json::json!({}), Some(err.to_string())),
+    };
+    if let Some(msg) = &error {
+        span.record("dd.error", true);
+        span.record("dd.meta.error.msg", msg.as_str());
+    }
+    dogstatsd::client().flush();
+    drop(_enter);
+    drop(span);
+    wait_pending_sends().await;
+    dd_extension::end_invocation(trace_id.0, execution_span_id.0, &response, error.as_deref()).await;
+    dd_extension::flush().await;
+    result
+}
+
+/// 任意のイベント(`LambdaEvent<T>`)を処理する際に挿入するヘルパー関数。
+/// トレース情報の取り出し方やRootSpanの内容は、イベントの型に実装された [TraceExtractor] に従う。
+pub async fn handle_event_with_trace<T, R, Fut>(
+    event: LambdaEvent<T>, f: impl FnOnce(LambdaEvent<T>) -> Fut,
+) -> Result<R, Error>
+where
+    T: TraceExtractor,
+    Fut: Future<Output = Result<R, Error>>,
+{
+    let invocation = Invocation::start();
+    let (trace_id, parent_id) = match event.payload.trace_context() {
+        Some(tc) => (TraceId(tc.trace_id), ParentSpanId(tc.parent_id)),
+        None => (TraceId::new(), ParentSpanId(0)),
+    };
+    TraceId::store(trace_id);
+    let inferred_spans = if CONFIG.managed_services {
+        event.payload.inferred_spans()
+    } else {
+        vec![]
+    };
+    let inferred_spans: Vec<Span> = inferred_spans
+        .iter()
+        .map(|info| inferred_span(trace_id, parent_id, info))
+        .collect();
+
+    // 推論Spanがあれば、先頭の推論Spanを親にする
+    let parent_id = inferred_spans
+        .first()
+        .and_then(|s| s.id())
+        .map_or(parent_id, |id| ParentSpanId(id.into_u64()));
+    let resource = event
+        .payload
+        .resource()
+        .unwrap_or_else(|| event.context.env_config.function_name.clone());
+    let span = root_span(
+        trace_id,
+        parent_id,
+        &event.context,
+        &invocation,
+        &resource,
+        event.payload.span_kind(),
+    );
+    for (key, value) in event.payload.tags().iter() {
+        set_tag(&span, key, value);
+    }
+    let _enter = span.enter();
+
+    let lambda_ctx = event.context.clone();
+    let parents = inferred_spans.iter().collect::<Vec<_>>();
+    let result = guard_timeout(&span, &parents, &lambda_ctx, Some(&invocation), f(event)).await;
+    if let Err(err) = &result {
+        span.record("dd.error", true);
+        span.record("dd.meta.error.msg", err.to_string());
+    }
+    enhanced_metrics::submit(&lambda_ctx, &invocation, result.is_err());
+    dogstatsd::client().flush();
+    // コールドスタートなら、Telemetry APIから届いている初期化時間をセットする
+    for (key, value) in telemetry_api::take_init_metrics(invocation.cold_start) {
+        set_metric(&span, key, value);
+    }
+    // Spanを閉じてから、Extensionにフラッシュを依頼する
+    drop(_enter);
+    telemetry_api::defer_root_span(&lambda_ctx, &invocation, &span);
+    drop(span);
+    inferred_spans.iter().for_each(finish_inferred_span);
+    drop(inferred_spans);
+    flush_to_extension().await;
+    result
+}
+
+/// DynamoDB Streamsのイベントを処理する際に挿入するヘルパー関数。
+/// `aws.dynamodb` の推論Spanを作成し、その子としてRootSpanを作成する。
+pub async fn handle_dynamodb_event_with_trace<R, Fut>(
+    event: LambdaEvent<dynamodb::Event>, f: impl FnOnce(LambdaEvent<dynamodb::Event>) -> Fut,
+) -> Result<R, Error>
+where
+    Fut: Future<Output = Result<R, Error>>,
+{
+    handle_event_with_trace(event, f).await
+}
+
+/// S3のイベント通知を処理する際に挿入するヘルパー関数。
+/// レコード毎に `aws.s3` の推論Spanを作成し、先頭のSpanの子としてRootSpanを作成する。
+/// 推論Spanにはバケット名、オブジェクトキー、サイズ、イベント名がセットされる。
+pub async fn handle_s3_event_with_trace<R, Fut>(
+    event: LambdaEvent<S3Event>, f: impl FnOnce(LambdaEvent<S3Event>) -> Fut,
+) -> Result<R, Error>
+where
+    Fut: Future<Output = Result<R, Error>>,
+{
+    handle_event_with_trace(event, f).await
+}
+
+/// Step Functionsのタスクとして呼ばれた際に挿入するヘルパー関数。
+/// ペイロードにStep FunctionsのContextオブジェクトが含まれていれば、実行ARN等からトレースIDと親SpanIDを決定的に算出する。
+pub async fn handle_step_functions_event_with_trace<R, Fut>(
+    event: LambdaEvent<serde_json::Value>, f: impl FnOnce(LambdaEvent<serde_json::Value>) -> Fut,
+) -> Result<R, Error>
+where
+    Fut: Future<Output = Result<R, Error>>,
+{
+    handle_event_with_trace(event, f).await
+}
+
+/// 送信中のSpanが全て送り終わるのを待ってから、Datadog Lambda Extensionにフラッシュを依頼する
+async fn flush_to_extension() {
+    if !dd_extension::is_available() {
+        return;
+    }
+    wait_pending_sends().await;
+    dd_extension::flush().await;
+}
+
+/// 送信中のSpanとメトリクスを全て送り切る。実行環境のShutdown時に呼ぶ
+pub(crate) async fn flush_all() {
+    dogstatsd::client().flush();
+    wait_pending_sends().await;
+    dd_extension::flush().await;
+}
+
+/// datadog-agent(Extension)へ送信中のSpanが全て送り終わるのを待つ
+async fn wait_pending_sends() {
+    let pending: Vec<JoinHandle<()>> = PENDING_SENDS.lock().unwrap().drain(..).collect();
+    for handle in pending {
+        let _ = handle.await;
+    }
+}
+
+/// Lambdaのタイムアウト直前になっても処理が終わらない場合、RootSpanをエラーにして、その時点で終了したものとしてフラッシュする。
+/// RootSpanには `error.type=Impending Timeout` がセットされる。タイミングは `DD_APM_FLUSH_DEADLINE_MILLISECONDS` (デフォルト100ms)で調整する。
+/// `parents` はRootSpanの親の推論Span。RootSpanと合わせてその時点で送信する。
+/// `invocation` を渡した場合は、拡張メトリクス(`invocations` / `timeouts` など)もその時点で送る。
+/// ハンドラの処理は打ち切らずにそのまま待つ(途中で止めると副作用が中途半端に残りうるため)。送信済みのSpanはクローズされても再送しない。
+/// `DD_CANCEL_ON_IMPENDING_TIMEOUT=true` の場合は処理を打ち切ってエラーとして返し、通常通りSpanをクローズして送信させる。
+/// 期限が取得できない場合や、既にマージンを切っている場合は何もしない。
+async fn guard_timeout<R>(
+    span: &Span, parents: &[&Span], lambda_ctx: &LambdaContext, invocation: Option<&Invocation>,
+    fut: impl Future<Output = Result<R, Error>>,
+) -> Result<R, Error> {
+    let Some(remaining) = lambda_meta::time_until_impending_timeout(lambda_ctx) else {
+        return fut.await;
+    };
+    tokio::pin!(fut);
+    tokio::select! {
+        result = &mut fut => return result,
+        _ = tokio::time::sleep(remaining) => {}
+    }
+    set_tag(span, "error.type", "Impending Timeout");
+    span.record("dd.error", true);
+    if let Some(invocation) = invocation {
+        enhanced_metrics::submit_impending_timeout(lambda_ctx, invocation);
+    }
+    if CONFIG.cancel_on_impending_timeout {
+        return Err(ImpendingTimeout.into());
+    }
+    // Lambdaに実行環境ごと止められるとSpanがクローズされないので、ここで送っておく
+    export_now(span);
+    parents.iter().for_each(|parent| export_now(parent));
+    dogstatsd::client().flush();
+    flush_to_extension().await;
+    fut.await
+}
+
+/// Spanをその時点で終了したものとしてdatadog-agentに送信する。以降にSpanがクローズされても再送しない
+fn export_now(span: &Span) {
+    span.with_subscriber(|(id, dispatch)| {
+        let (Some(registry), Some(layer)) = (dispatch.downcast_ref::<Registry>(), dispatch.downcast_ref::<TracingLayer>())
+        else {
+            return;
+        };
+        if let Some(span_ref) = registry.span(id) {
+            TracingLayer::with_dd_span(span_ref, |ds| {
+                ds.update_end();
+                layer.send_to_datadog_agent(ds);
+                ds.exported = true;
+            });
+        }
+    });
+}
+
+/// RootSpanを作成する。
+/// Datadogのサーバーレス向けのビューで扱えるように、Span名は `aws.lambda` とし関数のメタデータをタグにセットする。
+fn root_span(
+    trace_id: TraceId, parent_id: ParentSpanId, lambda_ctx: &LambdaContext, invocation: &Invocation, resource: &str,
+    kind: &'static str,
+) -> Span {
+    let span = info_span!(
+        "aws.lambda",
+        dd.trace_id = trace_id.0, // ログとトレースのマージのためにどこかでログ内にTraceIdを含めておきたい意図あり
+        dd.parent_id = parent_id.0,
+        // 以下任意でDatadogに渡したい値をセットして下さい。以下は一例です。
+        // 後から `span.record(..)` で更新するケースでも、このタイミングで宣言しておく必要があります。
+        dd.resource = resource,
+        dd.error = false,
+        dd.meta.span.kind = kind,
+        dd.meta.request_id = lambda_ctx.request_id.as_str(),
+        dd.meta.http.status_code = tracing::field::Empty,
+        dd.meta.error.msg = None::<String>,
+        dd.meta.cold_start = invocation.cold_start,
+        dd.meta.init_type = lambda_meta::initialization_type(),
+    );
+    for (key, value) in FunctionMeta::from_context(lambda_ctx).tags().iter() {
+        set_tag(&span, key, value);
+    }
+    span
+}
+
+/// 推論Spanを作成する。
+/// Span名や開始時刻はtracingのマクロでは動的に指定できないので、作成後にDDSpanを直接書き換える。
+fn inferred_span(trace_id: TraceId, parent_id: ParentSpanId, info: &InferredSpan) -> Span {
+    // lambda-runtimeが作成したSpanの子にならないよう、明示的に親無しで作成する
+    let span = info_span!(
+        parent: None,
+        "inferred_span",
+        dd.trace_id = trace_id.0,
+        dd.parent_id = parent_id.0,
+        dd.resource = info.resource.as_str(),
+        dd.error = false,
+        dd.meta.span.kind = info.kind,
+    );
+    with_dd_span_of(&span, |ds| {
+        ds.name = info.name.to_string();
+        ds.service = info.service.clone();
+        ds.start = DDSpan::utc_epoch_nanos(info.start);
+    });
+    for (key, value) in info.tags.iter() {
+        set_tag(&span, key, value);
+    }
+    for (key, value) in info.metrics.iter() {
+        set_metric(&span, key, *value);
+    }
+    span
+}
+
+/// 推論Spanの終了時刻をセットする。推論SpanはEnterされないので、クローズ前に明示的にセットしないとdurationが0になる
+fn finish_inferred_span(span: &Span) {
+    with_dd_span_of(span, |ds| ds.update_end());
+}
+
+/// Spanに文字列のタグ(meta)をセットする。
+/// tracingのフィールドは作成時に静的に宣言しておく必要があるので、キーが動的に決まるものはこちらを使う。
+pub(crate) fn set_tag(span: &Span, key: &str, value: &str) {
+    with_dd_span_of(span, |ds| {
+        ds.meta.insert(key.to_string(), value.to_string());
+    });
+}
+
+/// Spanに数値のメトリクスをセットする。
+pub(crate) fn set_metric(span: &Span, key: &str, value: f64) {
+    with_dd_span_of(span, |ds| {
+        ds.metrics.insert(key.to_string(), value);
+    });
+}
+
+/// tracingのSpanに紐づくDDSpanを操作する。
+fn with_dd_span_of(span: &Span, f: impl FnOnce(&mut DDSpan)) {
+    span.with_subscriber(|(id, dispatch)| {
+        if let Some(span_ref) = dispatch
+            .downcast_ref::<Registry>()
+            .and_then(|registry| registry.span(id))
+        {
+            TracingLayer::with_dd_span(span_ref, f);
+        }
+    });
+}
+
+/// クローズ後にタグを追加してから送るSpan。Telemetry APIのレコードが届くまでRootSpanを預かるのに使う
+pub(crate) struct PendingSpan {
+    span: DDSpan,
+    dispatch: Dispatch,
+}
+
+impl PendingSpan {
+    /// Spanの内容を取り出して預かる。以降、元のSpanはクローズされても送信しない。
+    /// タイムアウト直前に送信済みの場合はNone
+    pub fn take(span: &Span) -> Option<Self> {
+        span.with_subscriber(|(id, dispatch)| {
+            let span_ref = dispatch.downcast_ref::<Registry>()?.span(id)?;
+            let dd_span = span_ref.extensions_mut().remove::<DDSpan>()?;
+            (!dd_span.exported).then(|| PendingSpan {
+                span: dd_span,
+                dispatch: dispatch.clone(),
+            })
+        })
+        .flatten()
+    }
+
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        self.span.meta.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn set_metric(&mut self, key: &str, value: f64) {
+        self.span.metrics.insert(key.to_string(), value);
+    }
+
+    /// datadog-agentに送信する
+    pub fn export(mut self) {
+        if let Some(layer) = self.dispatch.downcast_ref::<TracingLayer>() {
+            layer.send_to_datadog_agent(&mut self.span);
+        }
+    }
+}
+
+/// Reqwestを使ったHTTP処理において、Datadog用のトレース処理を挿入する関数。
+/// 引数(リクエスト)やその他の属性をセットし、また処理結果をトレースに反映する。
+/// 全てのリクエストに自動で適用したい場合は [crate::traced_client::TracedClient] を使う。
+pub async fn request_http(
+    client: &reqwest::Client, mut req: reqwest::Request,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let span = client_span("reqwest.http", req.method().as_str(), req.url().as_str());
+    header_tags::set_request_tags(&span, req.headers());
+    let _enter = span.enter();
+
     // リクエストヘッダにトレーシング用ヘッダを追加
-    let trace_id = TraceId::get_current();
-    let h = req.headers_mut();
-    h.insert(TRACE_ID_HEADER, trace_id.0.into());
-    h.insert(PARENT_ID_HEADER, span.id().unwrap().into_u64().into());
+    inject_headers(&span, req.headers_mut());
     match client.execute(req).await {
         Ok(ret) => {
-            span.record("dd.meta.http.status_code", ret.status().as_u16());
-            // レスポンスが5xxならエラーフラグを立てる。
-            // 200でも実質エラーという処理系の場合は適宜対応する事
-            if ret.status().as_u16() >= 500 {
-                span.record("dd.error", true);
-            }
+            record_client_response(&span, ret.status().as_u16(), ret.headers());
             Ok(ret)
         }
         Err(err) => {
-            span.record("dd.error", true);
-            span.record("dd.meta.error.msg", err.to_string());
+            record_client_error(&span, &err.to_string());
             Err(err)
         }
     }
 }
 
+/// 外部へのHTTPアクセスを表すSpanを作成する。URLはサニタイズしてからセットする(url_sanitizer参照)
+/// 接続先を表すタグ(`peer.service` など)もセットする。
+/// Span名はクライアントの種類毎に呼び出し側で指定する(Reqwestなら `reqwest.http`、towerのクライアントなら `http.request`)
+pub(crate) fn client_span(name: &str, method: &str, url: &str) -> Span {
+    let sanitized = url_sanitizer::sanitize(url);
+    let span = info_span!(
+        "http.client",
+        dd.resource = format!("{} {}", method, sanitized.resource).as_str(),
+        dd.error = false,
+        dd.meta.span.kind = "client",
+        dd.meta.http.url = sanitized.url.as_str(),
+        dd.meta.http.method = method,
+        dd.meta.http.status_code = tracing::field::Empty,
+        dd.meta.error.msg = None::<String>,
+    );
+    with_dd_span_of(&span, |ds| ds.name = name.to_string());
+    for (key, value) in peer_service::peer_tags(&sanitized).iter() {
+        set_tag(&span, key, value);
+    }
+    span
+}
+
+/// リクエストヘッダに、指定したSpanを親とするトレーシング用ヘッダを挿入する
+pub(crate) fn inject_headers(span: &Span, headers: &mut HeaderMap) {
+    let trace_id = TraceId::get_current();
+    headers.insert(TRACE_ID_HEADER, trace_id.0.into());
+    if let Some(id) = span.id() {
+        headers.insert(PARENT_ID_HEADER, id.into_u64().into());
+    }
+}
+
+/// 指定したSpanを親とするトレーシング用ヘッダをMapとして取得する。
+/// メッセージ(SQSのメッセージ属性など)にトレース情報を埋め込む際に使う
+pub(crate) fn trace_headers(span: &Span) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    map.insert(TRACE_ID_HEADER.to_string(), TraceId::get_current().0.to_string());
+    if let Some(id) = span.id() {
+        map.insert(PARENT_ID_HEADER.to_string(), id.into_u64().to_string());
+    }
+    map
+}
+
+/// レスポンスのステータスコードをSpanに反映する。
+/// エラーとするかは [error_policy] に従う(デフォルトは5xx)。レスポンスヘッダは [header_tags] に従ってタグにする
+pub(crate) fn record_client_response(span: &Span, status: u16, headers: &HeaderMap) {
+    span.record("dd.meta.http.status_code", status);
+    header_tags::set_response_tags(span, headers);
+    let res = ResponseInfo {
+        status,
+        headers,
+        body: None,
+    };
+    if error_policy::is_client_error(&res) {
+        span.record("dd.error", true);
+    }
+}
+
+/// AWS SDKの呼び出しを表すSpanを作成する。Span名は `aws.<service>` (`aws.dynamodb` など)。
+/// ステータス・エラーは [record_client_response] / [record_client_error] で反映する。
+#[cfg(feature = "aws_sdk")]
+pub(crate) fn aws_client_span(service: &str, operation: &str) -> Span {
+    let span = info_span!(
+        "aws_sdk",
+        dd.resource = format!("{}.{}", service, operation).as_str(),
+        dd.error = false,
+        dd.meta.span.kind = "client",
+        dd.meta.http.status_code = tracing::field::Empty,
+        dd.meta.error.msg = None::<String>,
+    );
+    with_dd_span_of(&span, |ds| {
+        ds.name = format!("aws.{}", service);
+        ds.meta.insert("aws.service".to_string(), service.to_string());
+        ds.meta.insert("aws.operation".to_string(), operation.to_string());
+    });
+    span
+}
+
+/// 通信エラーをSpanに反映する
+pub(crate) fn record_client_error(span: &Span, msg: &str) {
+    span.record("dd.error", true);
+    span.record("dd.meta.error.msg", msg);
+}
+
 thread_local!(static TRACE_ID: RefCell<TraceId> = RefCell::new(TraceId::new()));
 const TRACE_ID_HEADER: &str = "x-datadog-trace-id";
 const PARENT_ID_HEADER: &str = "x-datadog-parent-id";
@@ -166,16 +663,21 @@ fn gen_span_id() -> u64 {
 
 struct TracingConfig {
     pub service_name: String,
+    pub agent_endpoint: String,
 }
 
 impl Default for TracingConfig {
     fn default() -> Self {
         TracingConfig {
             service_name: "".to_string(),
+            agent_endpoint: "http://localhost:8126".to_string(),
         }
     }
 }
 
+/// datadog-agent(Extension)へ送信中のタスク
+static PENDING_SENDS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
 pub struct TracingLayer {
     config: TracingConfig,
     client: reqwest::Client,
@@ -194,13 +696,22 @@ impl TracingLayer {
         self
     }
 
+    /// datadog-agent(Extension)のURL。デフォルトは `http://localhost:8126`
+    pub fn with_agent_endpoint(mut self, endpoint: &str) -> Self {
+        self.config.agent_endpoint = endpoint.to_string();
+        self
+    }
+
     fn send_to_datadog_agent(&self, span: &mut DDSpan) {
-        span.service = self.config.service_name.to_owned();
+        // 推論Spanなど、別のサービスとして送るSpanは個別にセット済み
+        if span.service.is_empty() {
+            span.service = self.config.service_name.to_owned();
+        }
         let json = serde_json::to_string(&span).unwrap();
         let body = format!("[[{}]]", json); // spanを複数同時に送信可能だがlocalhost宛なので、、
-        let endpoint = "http://localhost:8126/v0.3/traces";
+        let endpoint = format!("{}/v0.3/traces", self.config.agent_endpoint);
         let client = self.client.clone();
-        tokio::spawn(async move {
+        let handle = tokio::spawn(async move {
             // println!("@@ will send to ddagent: {}", body);
             if let Err(e) = client
                 .post(endpoint)
@@ -214,6 +725,10 @@ impl TracingLayer {
                 // }
             }
         });
+        // Extensionへのフラッシュ依頼やShutdownの前に送信完了を待てるように保持しておく。送信済みのものは捨てる
+        let mut pending = PENDING_SENDS.lock().unwrap();
+        pending.retain(|h| !h.is_finished());
+        pending.push(handle);
     }
 
     fn with_dd_span<'a, S>(span: SpanRef<'a, S>, f: impl FnOnce(&mut DDSpan))
@@ -289,9 +804,13 @@ where
         Self::with_dd_span(ctx.span(id).unwrap(), |ds| ds.update_end());
     }
 
-    /// SpanがクローズされたらDDSpanをDatadogに送信する。
+    /// SpanがクローズされたらDDSpanをDatadogに送信する。タイムアウト直前に送信済みのものは送らない。
     fn on_close(&self, id: Id, ctx: Context<'_, S>) {
-        Self::with_dd_span(ctx.span(&id).unwrap(), |ds| self.send_to_datadog_agent(ds));
+        Self::with_dd_span(ctx.span(&id).unwrap(), |ds| {
+            if !ds.exported {
+                self.send_to_datadog_agent(ds)
+            }
+        });
     }
 }
 
@@ -312,6 +831,9 @@ struct DDSpan {
     meta: HashMap<String, String>,
     metrics: HashMap<String, f64>,
     r#type: String,
+    /// クローズ前に送信済みかどうか
+    #[serde(skip)]
+    exported: bool,
 }
 
 impl DDSpan {
@@ -333,7 +855,8 @@ impl DDSpan {
 
     fn update_end(&mut self) {
         let n = Self::utc_epoch_nanos(Utc::now());
-        self.duration = n - self.start;
+        // 推論Spanの開始時刻は外部のタイムスタンプなので、時計のずれで未来になっている事もある
+        self.duration = n.saturating_sub(self.start);
     }
 }
 
@@ -352,6 +875,7 @@ impl Default for DDSpan {
             meta: Default::default(),
             metrics: Default::default(),
             r#type: "".to_string(),
+            exported: false,
         }
     }
 }
@@ -393,6 +917,9 @@ impl tracing::field::Visit for DDSpanUpdator<'_> {
             "dd.error" => {
                 self.0.error = i32::from(value) // trueなら1;
             }
+            "dd.meta.cold_start" => {
+                self.0.meta.insert("cold_start".to_string(), value.to_string());
+            }
             _ => {}
         }
     }
@@ -419,6 +946,9 @@ impl tracing::field::Visit for DDSpanUpdator<'_> {
             "dd.meta.http.method" => {
                 self.0.meta.insert("http.method".to_string(), value.to_string());
             }
+            "dd.meta.init_type" => {
+                self.0.meta.insert("init_type".to_string(), value.to_string());
+            }
             _ => {}
         }
     }
@@ -429,3 +959,107 @@ impl tracing::field::Visit for DDSpanUpdator<'_> {
         warn!("{} is not yet implemented", field.name());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+    use serde_json::Value;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// datadog-agentの代わりに受け取ったSpanを記録するHTTPサーバを立てて、そのURLを返す
+    fn stand_in_agent(spans: Arc<Mutex<Vec<Value>>>) -> String {
+        let make_service = make_service_fn(move |_| {
+            let spans = spans.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    let spans = spans.clone();
+                    async move {
+                        let bytes = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let traces: Vec<Vec<Value>> = serde_json::from_slice(&bytes).unwrap();
+                        spans.lock().unwrap().extend(traces.into_iter().flatten());
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    /// 指定した時間後にタイムアウト直前になるContext
+    fn context_timing_out_in(after: Duration) -> LambdaContext {
+        let mut ctx = LambdaContext::default();
+        ctx.deadline =
+            Utc::now().timestamp_millis() as u64 + CONFIG.flush_deadline_margin_ms + after.as_millis() as u64;
+        ctx
+    }
+
+    #[tokio::test]
+    async fn exports_flagged_root_span_on_impending_timeout() {
+        let spans = Arc::new(Mutex::new(vec![]));
+        let layer = TracingLayer::new()
+            .with_service_name("test")
+            .with_agent_endpoint(&stand_in_agent(spans.clone()));
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+        let span = info_span!("aws.lambda", dd.error = false);
+        let enter = span.enter();
+        let received = spans.clone();
+        let ctx = context_timing_out_in(Duration::from_millis(20));
+        let result = guard_timeout(&span, &[], &ctx, None, async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            // ハンドラの処理が終わる前に送信されている
+            Ok::<_, Error>(received.lock().unwrap().clone())
+        })
+        .await;
+
+        let received = result.unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["name"], "aws.lambda");
+        assert_eq!(received[0]["error"], 1);
+        assert_eq!(received[0]["meta"]["error.type"], "Impending Timeout");
+        assert!(received[0]["duration"].as_u64().unwrap() > 0);
+
+        // クローズされても再送しない
+        drop(enter);
+        drop(span);
+        wait_pending_sends().await;
+        assert_eq!(spans.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sets_duration_of_inferred_span() {
+        let spans = Arc::new(Mutex::new(vec![]));
+        let layer = TracingLayer::new()
+            .with_service_name("test")
+            .with_agent_endpoint(&stand_in_agent(spans.clone()));
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+        let info = InferredSpan {
+            name: "aws.dynamodb",
+            service: "aws.dynamodb".to_string(),
+            resource: "INSERT orders".to_string(),
+            kind: "consumer",
+            start: Utc::now() - chrono::Duration::milliseconds(50),
+            tags: vec![],
+            metrics: vec![],
+        };
+        let span = inferred_span(TraceId(1), ParentSpanId(0), &info);
+        finish_inferred_span(&span);
+        drop(span);
+        wait_pending_sends().await;
+
+        let received = spans.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["name"], "aws.dynamodb");
+        assert!(received[0]["duration"].as_u64().unwrap() >= 50_000_000);
+    }
+}
@@ -0,0 +1,195 @@
+//! Datadog Lambda Extensionとの連携。
+//!
+//! 本番ではdatadog-agentの代わりにLambda Extensionとして動くDatadog Lambda Extensionを使う。
+//! Extensionは呼び出しの終了を自分では知る事ができないので、呼び出し毎に `/lambda/flush` をPOSTして
+//! 受け取ったトレースやメトリクスをDatadogに送らせる必要がある(送らせないと実行環境のフリーズで取りこぼす)。
+//!
+//! Extensionの有無は `/opt/extensions/datadog-agent` の存在で判定する。
+//! `DD_EXTENSION_URL` を指定した場合はExtensionが有るものとして扱い、そのURLに送る。
+//! ローカルで代替のHTTPサーバを立てて動作確認する場合に使う。
+//!
+//! `DD_UNIVERSAL_INSTRUMENTATION=true` の場合は、Extensionのuniversal instrumentationを使う。
+//! 呼び出しの開始時にイベントを `/lambda/start-invocation` に、終了時に結果を `/lambda/end-invocation` に送る事で、
+//! RootSpan(`aws.lambda`)や推論SpanはExtension側で作成される。
+//! トレースIDはstart-invocationのレスポンスヘッダで受け取り、ExtensionのRootSpanのIDはこちらで採番してend-invocationで渡す。
+//! これによりExtensionのSpanとこちらのSpanが1つのトレースに繋がる。
+
+use crate::config::CONFIG;
+use crate::trace_extractor::TraceContext;
+use lambda_http::http::header::CONTENT_TYPE;
+use lambda_http::request::RequestContext;
+use lambda_http::{Body, Request, RequestExt};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::time::Duration;
+use tracing::warn;
+
+/// Datadog Lambda Extensionの実行ファイルのパス
+const EXTENSION_PATH: &str = "/opt/extensions/datadog-agent";
+const DEFAULT_URL: &str = "http://127.0.0.1:8124";
+const DD_TRACE_ID_HEADER: &str = "x-datadog-trace-id";
+const DD_SPAN_ID_HEADER: &str = "x-datadog-span-id";
+const DD_SAMPLING_PRIORITY_HEADER: &str = "x-datadog-sampling-priority";
+const DD_INVOCATION_ERROR_HEADER: &str = "x-datadog-invocation-error";
+const DD_INVOCATION_ERROR_MSG_HEADER: &str = "x-datadog-invocation-error-msg";
+const REQUEST_ID_HEADER: &str = "lambda-runtime-aws-request-id";
+/// フラッシュの完了を待つ最大時間。Extensionが応答しない場合にレスポンスを遅らせ続けないように
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+static AVAILABLE: Lazy<bool> = Lazy::new(|| CONFIG.extension_url.is_some() || Path::new(EXTENSION_PATH).exists());
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// Datadog Lambda Extensionが使えるかどうか
+pub(crate) fn is_available() -> bool {
+    *AVAILABLE
+}
+
+/// Extensionのuniversal instrumentationを使うかどうか
+pub(crate) fn use_universal_instrumentation() -> bool {
+    CONFIG.universal_instrumentation && is_available()
+}
+
+/// ExtensionのURL
+pub(crate) fn base_url() -> &'static str {
+    CONFIG.extension_url.as_deref().unwrap_or(DEFAULT_URL)
+}
+
+/// Extensionに、受け取ったトレースやメトリクスをDatadogに送るよう依頼する。
+/// Extensionが無い場合は何もしない。失敗してもログを出すのみ。
+pub(crate) async fn flush() {
+    if !is_available() {
+        return;
+    }
+    flush_to(base_url()).await;
+}
+
+async fn flush_to(base_url: &str) {
+    let result = CLIENT
+        .post(format!("{}/lambda/flush", base_url))
+        .timeout(FLUSH_TIMEOUT)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    if let Err(e) = result {
+        warn!("failed to flush datadog extension: {:?}", e);
+    }
+}
+
+/// 呼び出しの開始をExtensionに通知する。
+/// Extensionはイベントの内容から推論Spanを作成し、イベントに含まれるトレースヘッダを引き継ぐ(無ければ新規採番する)。
+/// レスポンスヘッダで返されたトレース情報を返す。失敗した場合はNone。
+pub(crate) async fn start_invocation(request_id: &str, payload: &Value) -> Option<TraceContext> {
+    let result = CLIENT
+        .post(format!("{}/lambda/start-invocation", base_url()))
+        .header(REQUEST_ID_HEADER, request_id)
+        .header(CONTENT_TYPE, "application/json")
+        .body(payload.to_string())
+        .timeout(FLUSH_TIMEOUT)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    match result {
+        Ok(res) => TraceContext::from_header_map(res.headers()),
+        Err(e) => {
+            warn!("failed to start invocation on datadog extension: {:?}", e);
+            None
+        }
+    }
+}
+
+/// 呼び出しの終了をExtensionに通知する。
+/// `span_id` はExtensionが作成するRootSpanのIDになるので、こちらのSpanの親として指定したものと同じIDを渡す。
+/// `error` はハンドラが返したエラー(もしくはエラー扱いにしたレスポンス)のメッセージ。
+pub(crate) async fn end_invocation(trace_id: u64, span_id: u64, response: &Value, error: Option<&str>) {
+    let mut builder = CLIENT
+        .post(format!("{}/lambda/end-invocation", base_url()))
+        .header(DD_TRACE_ID_HEADER, trace_id.to_string())
+        .header(DD_SPAN_ID_HEADER, span_id.to_string())
+        .header(DD_SAMPLING_PRIORITY_HEADER, "1") // SamplingPriority.AutoKeep
+        .header(CONTENT_TYPE, "application/json")
+        .body(response.to_string())
+        .timeout(FLUSH_TIMEOUT);
+    if let Some(msg) = error {
+        builder = builder
+            .header(DD_INVOCATION_ERROR_HEADER, "true")
+            // ヘッダに含められない文字は落とす
+            .header(
+                DD_INVOCATION_ERROR_MSG_HEADER,
+                msg.chars()
+                    .filter(|c| c.is_ascii() && !c.is_ascii_control())
+                    .collect::<String>(),
+            );
+    }
+    let result = builder.send().await.and_then(|res| res.error_for_status());
+    if let Err(e) = result {
+        warn!("failed to end invocation on datadog extension: {:?}", e);
+    }
+}
+
+/// start-invocationに送るために、lambda_httpのRequestを元のAPI Gatewayのイベントの形式に戻す。
+/// Extensionが推論Spanの作成やトレースヘッダの引き継ぎに使う項目のみ。
+pub(crate) fn request_payload(req: &Request) -> Value {
+    let RequestContext::ApiGatewayV1(request_context) = req.request_context();
+    let headers: serde_json::Map<String, Value> = req
+        .headers()
+        .iter()
+        .filter_map(|(k, v)| Some((k.to_string(), Value::from(v.to_str().ok()?))))
+        .collect();
+    let body = match req.body() {
+        Body::Text(text) => Value::from(text.as_str()),
+        _ => Value::Null,
+    };
+    json!({
+        "resource": request_context.resource_path,
+        "path": request_context.path,
+        "httpMethod": req.method().as_str(),
+        "headers": headers,
+        "requestContext": request_context,
+        "body": body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    /// Extensionの代わりにリクエストのパスを記録するHTTPサーバを立てて、そのURLを返す
+    fn stand_in_extension(paths: Arc<Mutex<Vec<String>>>) -> String {
+        let make_service = make_service_fn(move |_| {
+            let paths = paths.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    paths.lock().unwrap().push(req.uri().path().to_string());
+                    async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn flush_posts_to_extension() {
+        let paths = Arc::new(Mutex::new(vec![]));
+        let url = stand_in_extension(paths.clone());
+        flush_to(&url).await;
+        assert_eq!(*paths.lock().unwrap(), vec!["/lambda/flush".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn flush_gives_up_when_extension_is_unreachable() {
+        // 接続できなくてもパニックせず、タイムアウト内に戻る
+        let started = std::time::Instant::now();
+        flush_to("http://127.0.0.1:1").await;
+        assert!(started.elapsed() <= FLUSH_TIMEOUT + Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,383 @@
+//! DogStatsDクライアント。
+//!
+//! カスタムメトリクスを呼び出し中はプロセス内で集計しておき、呼び出しの最後(各helperの `handle_*_with_trace` の終了時)に
+//! まとめてDogStatsD(datadog-agentやDatadog Lambda Extension)に送る。
+//! Lambdaは処理が終わると実行環境がフリーズされるので、レスポンスを返す前に送っておく必要がある。
+//!
+//! 送信先は以下の環境変数で指定する。
+//! - `DD_DOGSTATSD_SOCKET`: Unixドメインソケットのパス。指定された場合はこちらを優先する
+//! - `DD_DOGSTATSD_HOST` / `DD_DOGSTATSD_PORT`: UDPの送信先。デフォルトは `127.0.0.1:8125`
+//!
+//! ## Example
+//! ```
+//! let statsd = dogstatsd::client();
+//! statsd.count("order.created", 1, &["shop:tokyo"]);
+//! statsd.distribution("order.amount", 333.0, &["shop:tokyo"]);
+//! ```
+
+use crate::config::CONFIG;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+use tracing::warn;
+
+/// UDPで1パケットに詰める最大サイズ(一般的なMTUに収まるサイズ)
+const UDP_MAX_PAYLOAD: usize = 1432;
+/// Unixドメインソケットで1パケットに詰める最大サイズ
+const UDS_MAX_PAYLOAD: usize = 8192;
+
+static CLIENT: Lazy<DogStatsd> = Lazy::new(DogStatsd::from_env);
+
+/// 共有のクライアントを取得する
+pub(crate) fn client() -> &'static DogStatsd {
+    &CLIENT
+}
+
+pub(crate) struct DogStatsd {
+    sink: Option<Sink>,
+    aggregator: Mutex<HashMap<MetricKey, MetricValue>>,
+}
+
+enum Sink {
+    Udp(UdpSocket),
+    Uds(UnixDatagram),
+}
+
+impl Sink {
+    fn send(&self, payload: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Udp(socket) => socket.send(payload),
+            Sink::Uds(socket) => socket.send(payload),
+        }
+    }
+
+    fn max_payload(&self) -> usize {
+        match self {
+            Sink::Udp(_) => UDP_MAX_PAYLOAD,
+            Sink::Uds(_) => UDS_MAX_PAYLOAD,
+        }
+    }
+}
+
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+struct MetricKey {
+    name: String,
+    /// 順序の違いで別のメトリクスにならないよう、ソートしてから `,` で結合したもの
+    tags: String,
+    kind: MetricKind,
+}
+
+#[derive(Hash, Eq, PartialEq, Copy, Clone, Debug)]
+enum MetricKind {
+    Count,
+    Gauge,
+    Histogram,
+    Distribution,
+    Set,
+}
+
+impl MetricKind {
+    fn type_str(&self) -> &'static str {
+        match self {
+            MetricKind::Count => "c",
+            MetricKind::Gauge => "g",
+            MetricKind::Histogram => "h",
+            MetricKind::Distribution => "d",
+            MetricKind::Set => "s",
+        }
+    }
+}
+
+/// 集計中の値
+#[derive(Debug)]
+enum MetricValue {
+    /// 合計する
+    Count(i64),
+    /// 最後の値のみ残す
+    Gauge(f64),
+    /// 全ての値を残す(Histogram/Distribution)
+    Samples(Vec<f64>),
+    /// ユニークな値を残す
+    Set(HashSet<String>),
+}
+
+impl DogStatsd {
+    fn from_env() -> Self {
+        let sink = match Self::connect() {
+            Ok(sink) => Some(sink),
+            Err(e) => {
+                warn!("dogstatsd is disabled. failed to connect: {:?}", e);
+                None
+            }
+        };
+        DogStatsd {
+            sink,
+            aggregator: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn connect() -> io::Result<Sink> {
+        if let Some(path) = &CONFIG.dogstatsd_socket {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            socket.set_nonblocking(true)?;
+            return Ok(Sink::Uds(socket));
+        }
+        let addr = (CONFIG.dogstatsd_host.as_str(), CONFIG.dogstatsd_port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "dogstatsd host not found"))?;
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Sink::Udp(socket))
+    }
+
+    /// カウンタ。呼び出し中の値は合計される
+    pub fn count(&self, name: &str, value: i64, tags: &[&str]) {
+        self.aggregate(name, tags, MetricKind::Count, |v| match v {
+            MetricValue::Count(sum) => *sum += value,
+            _ => *v = MetricValue::Count(value),
+        });
+    }
+
+    /// ゲージ。呼び出し中の最後の値のみ送られる
+    pub fn gauge(&self, name: &str, value: f64, tags: &[&str]) {
+        self.aggregate(name, tags, MetricKind::Gauge, |v| *v = MetricValue::Gauge(value));
+    }
+
+    /// ヒストグラム。集計はdatadog-agent側で行われる
+    pub fn histogram(&self, name: &str, value: f64, tags: &[&str]) {
+        self.aggregate(name, tags, MetricKind::Histogram, |v| v.push_sample(value));
+    }
+
+    /// ディストリビューション。集計はDatadog側で行われる
+    pub fn distribution(&self, name: &str, value: f64, tags: &[&str]) {
+        self.aggregate(name, tags, MetricKind::Distribution, |v| v.push_sample(value));
+    }
+
+    /// セット。ユニークな値の数がカウントされる
+    pub fn set(&self, name: &str, value: &str, tags: &[&str]) {
+        self.aggregate(name, tags, MetricKind::Set, |v| match v {
+            MetricValue::Set(values) => {
+                values.insert(value.to_string());
+            }
+            _ => *v = MetricValue::Set(HashSet::from([value.to_string()])),
+        });
+    }
+
+    fn aggregate(&self, name: &str, tags: &[&str], kind: MetricKind, f: impl FnOnce(&mut MetricValue)) {
+        let mut tags: Vec<&str> = tags.to_vec();
+        tags.sort_unstable();
+        let key = MetricKey {
+            name: name.to_string(),
+            tags: tags.join(","),
+            kind,
+        };
+        let mut aggregator = self.aggregator.lock().unwrap();
+        // 初期値はf側で上書きされる
+        let value = aggregator.entry(key).or_insert(MetricValue::Samples(vec![]));
+        f(value);
+    }
+
+    /// 集計中のメトリクスを全て送信する。
+    /// ノンブロッキングで送信するので、送信先が詰まっている場合などは破棄される。
+    pub fn flush(&self) {
+        let metrics: Vec<(MetricKey, MetricValue)> = self.aggregator.lock().unwrap().drain().collect();
+        let sink = match &self.sink {
+            Some(sink) if !metrics.is_empty() => sink,
+            _ => return,
+        };
+
+        let mut payload = String::new();
+        for (key, value) in metrics.iter() {
+            for line in Self::serialize(key, value) {
+                if !payload.is_empty() && payload.len() + line.len() + 1 > sink.max_payload() {
+                    Self::send(sink, &payload);
+                    payload.clear();
+                }
+                if !payload.is_empty() {
+                    payload.push('\n');
+                }
+                payload.push_str(&line);
+            }
+        }
+        if !payload.is_empty() {
+            Self::send(sink, &payload);
+        }
+    }
+
+    fn send(sink: &Sink, payload: &str) {
+        if let Err(e) = sink.send(payload.as_bytes()) {
+            warn!("dogstatsd failed to send metrics: {:?}", e);
+        }
+    }
+
+    /// DogStatsDのプロトコルの形式にする。
+    /// Histogram/Distributionは `name:1:2:3|d|#tags` のように複数の値を1行にまとめる(DogStatsD protocol v1.1)。
+    fn serialize(key: &MetricKey, value: &MetricValue) -> Vec<String> {
+        let values: Vec<String> = match value {
+            MetricValue::Count(sum) => vec![sum.to_string()],
+            MetricValue::Gauge(v) => vec![v.to_string()],
+            MetricValue::Samples(samples) => samples
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .chunks(64)
+                .map(|chunk| chunk.join(":"))
+                .collect(),
+            MetricValue::Set(values) => values.iter().cloned().collect(),
+        };
+        values
+            .into_iter()
+            .map(|v| {
+                let mut line = format!("{}:{}|{}", key.name, v, key.kind.type_str());
+                if !key.tags.is_empty() {
+                    let _ = write!(line, "|#{}", key.tags);
+                }
+                line
+            })
+            .collect()
+    }
+}
+
+impl MetricValue {
+    fn push_sample(&mut self, value: f64) {
+        match self {
+            MetricValue::Samples(samples) => samples.push(value),
+            _ => *self = MetricValue::Samples(vec![value]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// 127.0.0.1のエフェメラルポートで受信するソケットと、そこに送るクライアントを作る
+    fn client_with_receiver() -> (DogStatsd, UdpSocket) {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(receiver.local_addr().unwrap()).unwrap();
+        let client = DogStatsd {
+            sink: Some(Sink::Udp(socket)),
+            aggregator: Mutex::new(HashMap::new()),
+        };
+        (client, receiver)
+    }
+
+    /// 届いたデータグラムを全て受け取る
+    fn receive(receiver: &UdpSocket) -> Vec<String> {
+        let mut datagrams = vec![];
+        let mut buf = [0u8; 65536];
+        while let Ok(n) = receiver.recv(&mut buf) {
+            datagrams.push(String::from_utf8(buf[..n].to_vec()).unwrap());
+        }
+        datagrams
+    }
+
+    /// 届いた行をソートして返す。HashMapから取り出すので送信順は不定
+    fn receive_lines(receiver: &UdpSocket) -> Vec<String> {
+        let mut lines: Vec<String> = receive(receiver)
+            .iter()
+            .flat_map(|datagram| datagram.lines().map(str::to_string).collect::<Vec<_>>())
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn sums_counts_with_same_tags() {
+        let (client, receiver) = client_with_receiver();
+        client.count("orders", 1, &["shop:tokyo", "env:prod"]);
+        client.count("orders", 2, &["env:prod", "shop:tokyo"]);
+        client.count("orders", 5, &[]);
+        client.flush();
+        assert_eq!(
+            receive_lines(&receiver),
+            vec!["orders:3|c|#env:prod,shop:tokyo", "orders:5|c"]
+        );
+
+        // 送信済みの値は残らない
+        client.flush();
+        assert!(receive(&receiver).is_empty());
+    }
+
+    #[test]
+    fn sends_last_gauge_value() {
+        let (client, receiver) = client_with_receiver();
+        client.gauge("queue.depth", 1.5, &["queue:a"]);
+        client.gauge("queue.depth", 2.5, &["queue:a"]);
+        client.flush();
+        assert_eq!(receive_lines(&receiver), vec!["queue.depth:2.5|g|#queue:a"]);
+    }
+
+    #[test]
+    fn packs_samples_into_multi_value_lines() {
+        let (client, receiver) = client_with_receiver();
+        client.histogram("latency", 1.0, &[]);
+        client.histogram("latency", 2.5, &[]);
+        client.distribution("amount", 100.0, &["shop:tokyo"]);
+        client.distribution("amount", 200.0, &["shop:tokyo"]);
+        client.flush();
+        assert_eq!(
+            receive_lines(&receiver),
+            vec!["amount:100:200|d|#shop:tokyo", "latency:1:2.5|h"]
+        );
+    }
+
+    #[test]
+    fn splits_samples_every_64_values() {
+        let (client, receiver) = client_with_receiver();
+        for i in 0..100 {
+            client.distribution("size", i as f64, &[]);
+        }
+        client.flush();
+        let lines = receive_lines(&receiver);
+        assert_eq!(lines.len(), 2);
+        let counts: Vec<usize> = lines
+            .iter()
+            .map(|line| {
+                line.trim_start_matches("size:")
+                    .trim_end_matches("|d")
+                    .split(':')
+                    .count()
+            })
+            .collect();
+        assert_eq!(counts.iter().sum::<usize>(), 100);
+        assert!(counts.contains(&64));
+    }
+
+    #[test]
+    fn sends_unique_set_values() {
+        let (client, receiver) = client_with_receiver();
+        client.set("users", "alice", &[]);
+        client.set("users", "bob", &[]);
+        client.set("users", "alice", &[]);
+        client.flush();
+        assert_eq!(receive_lines(&receiver), vec!["users:alice|s", "users:bob|s"]);
+    }
+
+    #[test]
+    fn splits_payload_at_max_payload() {
+        let (client, receiver) = client_with_receiver();
+        for i in 0..100 {
+            client.count(&format!("metric.with.a.fairly.long.name.{:03}", i), 1, &["env:test"]);
+        }
+        client.flush();
+        let datagrams = receive(&receiver);
+        assert!(datagrams.len() > 1);
+        for datagram in &datagrams {
+            assert!(datagram.len() <= UDP_MAX_PAYLOAD);
+            assert!(!datagram.starts_with('\n') && !datagram.ends_with('\n'));
+        }
+        let lines: usize = datagrams.iter().map(|datagram| datagram.lines().count()).sum();
+        assert_eq!(lines, 100);
+    }
+}
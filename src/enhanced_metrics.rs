@@ -0,0 +1,84 @@
+//! Lambdaの拡張メトリクス(`aws.lambda.enhanced.*`)。
+//!
+//! 呼び出し回数、エラー、処理時間、コールドスタート時の初期化時間、タイムアウトを
+//! 呼び出し毎にディストリビューションメトリクスとして送る。`DD_ENHANCED_METRICS=false` で無効化できる。
+//! 送信は [crate::metrics] を通して行うので、Datadog Lambda Extensionの有無で送信経路が切り替わる。
+//!
+//! エラーはRootSpanをエラーにしたのと同じ判定で数えるので、[crate::error_policy] でエラーとした5xxのレスポンスも含む。
+//! タイムアウト直前を検知した場合は、ハンドラの終了を待たずにその時点で送る([submit_impending_timeout])。
+//!
+//! メモリ不足はプロセスごと落ちるので、ここでは検知できない。タイムアウト直前を検知できずに実際にタイムアウトした場合も同様。
+//! これらは `DD_TELEMETRY_API=true` の場合のみ、Telemetry APIの `platform.report` から送る([crate::telemetry_api] 参照)。
+
+use crate::config::CONFIG;
+use crate::lambda_meta::{FunctionMeta, Invocation};
+use crate::metrics;
+use lambda_runtime::Context as LambdaContext;
+use std::sync::Mutex;
+
+const PREFIX: &str = "aws.lambda.enhanced";
+
+/// タイムアウト直前に `timeouts` を送った呼び出しのリクエストID。`platform.report` で二重に数えないため
+static TIMEOUT_SUBMITTED: Mutex<Option<String>> = Mutex::new(None);
+
+/// 呼び出し1回分の拡張メトリクスを送る。
+/// `is_error` はRootSpanをエラーにしたかどうか。ハンドラのエラーと、[crate::error_policy] でエラーとしたレスポンスが該当する。
+/// [submit_impending_timeout] で送信済みの場合は何もしない。
+pub(crate) fn submit(lambda_ctx: &LambdaContext, invocation: &Invocation, is_error: bool) {
+    submit_with(lambda_ctx, invocation, is_error, false);
+}
+
+/// タイムアウト直前に呼ぶ。このまま実行環境ごと止められるとハンドラの終了後には送れないので、この時点で送る。
+/// RootSpanもエラーにするので、エラーとしても数える
+pub(crate) fn submit_impending_timeout(lambda_ctx: &LambdaContext, invocation: &Invocation) {
+    submit_with(lambda_ctx, invocation, true, true);
+}
+
+/// `platform.report` の呼び出しについて、[submit_impending_timeout] で `timeouts` を送信済みならtrue
+pub(crate) fn take_timeout_submitted(request_id: &str) -> bool {
+    let mut submitted = TIMEOUT_SUBMITTED.lock().unwrap();
+    if submitted.as_deref() == Some(request_id) {
+        *submitted = None;
+        true
+    } else {
+        false
+    }
+}
+
+fn submit_with(lambda_ctx: &LambdaContext, invocation: &Invocation, is_error: bool, is_timeout: bool) {
+    if !CONFIG.enhanced_metrics || !invocation.mark_metrics_submitted() {
+        return;
+    }
+    let tags = tags(&FunctionMeta::from_context(lambda_ctx), invocation.cold_start);
+
+    emit("invocations", 1.0, &tags);
+    if is_error {
+        emit("errors", 1.0, &tags);
+    }
+    if is_timeout {
+        emit("timeouts", 1.0, &tags);
+        *TIMEOUT_SUBMITTED.lock().unwrap() = Some(lambda_ctx.request_id.clone());
+    }
+    emit("duration", invocation.elapsed().as_secs_f64(), &tags);
+    if let Some(init_duration) = invocation.init_duration() {
+        emit("init_duration", init_duration.as_secs_f64(), &tags);
+    }
+}
+
+/// 拡張メトリクスのタグ。Telemetry APIから送るものも、系列が揃うようにこれを使う
+pub(crate) fn tags(meta: &FunctionMeta, cold_start: bool) -> Vec<String> {
+    vec![
+        format!("functionname:{}", meta.function_name.to_lowercase()),
+        format!("executedversion:{}", meta.function_version),
+        format!("region:{}", meta.region),
+        format!("account_id:{}", meta.account_id),
+        format!("memorysize:{}", meta.memory_size),
+        format!("cold_start:{}", cold_start),
+    ]
+}
+
+/// `aws.lambda.enhanced.` を付けてディストリビューションメトリクスとして送る
+fn emit(name: &str, value: f64, tags: &[String]) {
+    let tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+    metrics::send_distribution_metric(&format!("{}.{}", PREFIX, name), value, &tags);
+}
@@ -0,0 +1,155 @@
+//! Spanをエラーとするかの判定。
+//!
+//! デフォルトではレスポンスのステータスコードが5xxの場合にエラーとする。
+//! 判定に使うステータスコードはSpanの種類毎に環境変数で変更できる(`500-599,429` のように範囲と単一の値をカンマ区切りで指定)。
+//! - サーバ(RootSpan・API Gatewayの推論Span): `DD_TRACE_HTTP_SERVER_ERROR_STATUSES`
+//! - クライアント(外部へのHTTPアクセス・AWS SDKの呼び出し): `DD_TRACE_HTTP_CLIENT_ERROR_STATUSES`
+//!
+//! ステータスが200でもボディやヘッダでエラーを返すAPIに対応するため、レスポンスを見て判定する述語も登録できる。
+//! ステータスが範囲内、もしくは述語がtrueを返した場合にエラーとする。述語の登録は最初の1回のみ有効。
+//! サーバ側では、ハンドラがバッファ済みのボディ(`lambda_http::Body` / `String` / `Vec<u8>`)を返した場合のみ述語にボディも渡す。
+//! クライアント側ではボディを読むとレスポンスを消費してしまうので渡さない。
+//!
+//! ## Example
+//! ```
+//! error_policy::set_server_error_predicate(|res| {
+//!     res.body.map_or(false, |body| body.starts_with(br#"{"error""#))
+//! });
+//! error_policy::set_client_error_predicate(|res| res.headers.contains_key("x-error-code"));
+//! ```
+
+use crate::config::CONFIG;
+use lambda_http::http::HeaderMap;
+use once_cell::sync::OnceCell;
+use std::ops::RangeInclusive;
+use tracing::warn;
+
+/// エラー判定に使うレスポンス
+#[derive(Debug, Clone, Copy)]
+pub struct ResponseInfo<'a> {
+    pub status: u16,
+    pub headers: &'a HeaderMap,
+    /// ボディ。取得できない場合(クライアント側やストリーミングのボディ)はNone
+    pub body: Option<&'a [u8]>,
+}
+
+/// レスポンスを受け取り、エラーならtrueを返す述語
+pub type ErrorPredicate = dyn Fn(&ResponseInfo<'_>) -> bool + Send + Sync;
+
+static SERVER_PREDICATE: OnceCell<Box<ErrorPredicate>> = OnceCell::new();
+static CLIENT_PREDICATE: OnceCell<Box<ErrorPredicate>> = OnceCell::new();
+
+/// サーバ側のSpanのエラー判定に使う述語を登録する
+pub fn set_server_error_predicate(f: impl Fn(&ResponseInfo<'_>) -> bool + Send + Sync + 'static) {
+    if SERVER_PREDICATE.set(Box::new(f)).is_err() {
+        warn!("server error predicate is already set");
+    }
+}
+
+/// クライアント側のSpanのエラー判定に使う述語を登録する
+pub fn set_client_error_predicate(f: impl Fn(&ResponseInfo<'_>) -> bool + Send + Sync + 'static) {
+    if CLIENT_PREDICATE.set(Box::new(f)).is_err() {
+        warn!("client error predicate is already set");
+    }
+}
+
+/// ハンドラが返したレスポンスをエラーとするか
+pub(crate) fn is_server_error(res: &ResponseInfo<'_>) -> bool {
+    is_error(
+        &CONFIG.server_error_statuses,
+        SERVER_PREDICATE.get().map(Box::as_ref),
+        res,
+    )
+}
+
+/// 外部へのアクセスのレスポンスをエラーとするか
+pub(crate) fn is_client_error(res: &ResponseInfo<'_>) -> bool {
+    is_error(
+        &CONFIG.client_error_statuses,
+        CLIENT_PREDICATE.get().map(Box::as_ref),
+        res,
+    )
+}
+
+fn is_error(statuses: &[RangeInclusive<u16>], predicate: Option<&ErrorPredicate>, res: &ResponseInfo<'_>) -> bool {
+    statuses.iter().any(|r| r.contains(&res.status)) || predicate.map_or(false, |f| f(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, headers: &HeaderMap) -> ResponseInfo<'_> {
+        ResponseInfo {
+            status,
+            headers,
+            body: None,
+        }
+    }
+
+    #[test]
+    fn matches_status_in_any_range() {
+        let statuses = [500..=599, 429..=429];
+        let headers = HeaderMap::new();
+        for (status, expected) in [
+            (200, false),
+            (428, false),
+            (429, true),
+            (499, false),
+            (500, true),
+            (599, true),
+            (600, false),
+        ] {
+            assert_eq!(
+                is_error(&statuses, None, &response(status, &headers)),
+                expected,
+                "{}",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn uses_predicate_in_addition_to_statuses() {
+        let predicate: Box<ErrorPredicate> =
+            Box::new(|res: &ResponseInfo<'_>| res.body.map_or(false, |body| body.starts_with(b"{\"error\"")));
+        let headers = HeaderMap::new();
+        let ok = ResponseInfo {
+            body: Some(br#"{"ok":true}"#),
+            ..response(200, &headers)
+        };
+        let error = ResponseInfo {
+            body: Some(br#"{"error":"invalid"}"#),
+            ..response(200, &headers)
+        };
+        assert!(!is_error(&[500..=599], Some(predicate.as_ref()), &ok));
+        assert!(is_error(&[500..=599], Some(predicate.as_ref()), &error));
+        assert!(is_error(
+            &[500..=599],
+            Some(predicate.as_ref()),
+            &response(503, &headers)
+        ));
+    }
+
+    #[test]
+    fn uses_default_statuses_and_registered_predicates() {
+        // 述語は最初の1回のみ登録できるので、他のテストに影響しないようにテスト用のヘッダでのみエラーとする
+        set_server_error_predicate(|res| res.headers.contains_key("x-test-server-error"));
+        set_client_error_predicate(|res| res.headers.contains_key("x-test-client-error"));
+        let empty = HeaderMap::new();
+        let mut server_error = HeaderMap::new();
+        server_error.insert("x-test-server-error", "1".parse().unwrap());
+        let mut client_error = HeaderMap::new();
+        client_error.insert("x-test-client-error", "1".parse().unwrap());
+
+        assert!(is_server_error(&response(500, &empty)));
+        assert!(!is_server_error(&response(404, &empty)));
+        assert!(is_server_error(&response(200, &server_error)));
+        assert!(!is_server_error(&response(200, &client_error)));
+
+        assert!(is_client_error(&response(502, &empty)));
+        assert!(!is_client_error(&response(429, &empty)));
+        assert!(is_client_error(&response(200, &client_error)));
+        assert!(!is_client_error(&response(200, &server_error)));
+    }
+}
@@ -0,0 +1,203 @@
+//! Lambda Extensions APIへの内部Extensionとしての登録。
+//!
+//! Lambdaは一定時間呼び出しが無いと実行環境をShutdownするが、その際アプリのコードは呼ばれないので、
+//! 送信待ちのSpanやメトリクスは取りこぼされる。
+//! Extensionを1つでも登録しておくと、Shutdown時にランタイムのプロセスにSIGTERMが送られ、
+//! 猶予期間(数百ms)の間に後始末ができるようになる。ここではそのための内部Extensionを登録し、
+//! SIGTERMを受け取ったら送信待ちのSpanとメトリクスを全て送り切る。
+//!
+//! なお内部Extension(ランタイムと同じプロセス内で登録するExtension)は `SHUTDOWN` イベントを購読できない(外部Extensionのみ)ので、
+//! 購読するのは `INVOKE` のみで、Shutdownの検知はSIGTERMで行う。
+//! Extensionとして登録した以上は呼び出し毎に `/event/next` を呼ぶ必要があるので、バックグラウンドでループさせる。
+//! 呼ばなくなるとLambdaは以降の呼び出しの度にタイムアウトまで待たされるので、失敗してもバックオフしながら呼び続ける。
+//! モック等から `SHUTDOWN` イベントが返された場合も同様に後始末する。
+//!
+//! `DD_SHUTDOWN_FLUSH=true` もしくは `DD_TELEMETRY_API=true` の場合のみ登録する。
+//! Telemetry APIの購読もこのExtensionとして行う([crate::telemetry_api] 参照)。
+//! 接続先は `AWS_LAMBDA_RUNTIME_API` に従うので、ローカルではモックのアドレスを指定すれば動作確認できる。
+
+use crate::config::CONFIG;
+use crate::helper;
+use crate::telemetry_api;
+use serde::Deserialize;
+use std::env;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+
+const EXTENSION_NAME: &str = "datadog-trace-helper";
+const EXTENSION_NAME_HEADER: &str = "Lambda-Extension-Name";
+const EXTENSION_ID_HEADER: &str = "Lambda-Extension-Identifier";
+/// `/event/next` が失敗した場合の再試行間隔の初期値と上限
+const RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// `/event/next` のレスポンス
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NextEvent {
+    event_type: String,
+}
+
+/// 内部Extensionとして登録し、Shutdown時の後始末とTelemetry APIの購読を行う。
+/// 登録に失敗してもログを出すのみで、関数自体の処理は続行する。
+pub(crate) async fn start() {
+    if !CONFIG.shutdown_flush && !CONFIG.telemetry_api {
+        return;
+    }
+    let runtime_api = match env::var("AWS_LAMBDA_RUNTIME_API") {
+        Ok(api) => api,
+        Err(_) => {
+            warn!("AWS_LAMBDA_RUNTIME_API is not set. extension is disabled.");
+            return;
+        }
+    };
+    let base_url = format!("http://{}/2020-01-01/extension", runtime_api);
+    let client = reqwest::Client::new();
+    let extension_id = match register(&client, &base_url).await {
+        Ok(id) => id,
+        Err(e) => {
+            warn!("failed to register extension: {:?}", e);
+            return;
+        }
+    };
+    info!(extension_id, "registered extension");
+    telemetry_api::start(&client, &runtime_api, &extension_id).await;
+
+    if CONFIG.shutdown_flush {
+        listen_sigterm();
+    }
+    tokio::spawn(async move {
+        wait_shutdown(&client, &base_url, &extension_id).await;
+        helper::flush_all().await;
+    });
+}
+
+/// `SHUTDOWN` イベントが返されるまで `/event/next` を呼び続ける。失敗した場合はバックオフして再試行する
+async fn wait_shutdown(client: &reqwest::Client, base_url: &str, extension_id: &str) {
+    let mut backoff = RETRY_INITIAL_BACKOFF;
+    loop {
+        match next_event(client, base_url, extension_id).await {
+            Ok(event) if event.event_type == "SHUTDOWN" => return,
+            Ok(_) => backoff = RETRY_INITIAL_BACKOFF,
+            Err(e) => {
+                warn!("failed to get next extension event. retry after {:?}: {:?}", backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(RETRY_MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// SIGTERM(実行環境のShutdown)を受け取ったら、送信待ちのSpanとメトリクスを全て送り切って終了する
+fn listen_sigterm() {
+    tokio::spawn(async move {
+        let mut sigterm = match signal(SignalKind::terminate()) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("failed to listen SIGTERM: {:?}", e);
+                return;
+            }
+        };
+        sigterm.recv().await;
+        info!("received SIGTERM. flush all spans and metrics.");
+        helper::flush_all().await;
+        std::process::exit(0);
+    });
+}
+
+/// `INVOKE` イベントを購読するExtensionとして登録し、ExtensionのIDを返す
+async fn register(client: &reqwest::Client, base_url: &str) -> Result<String, reqwest::Error> {
+    let res = client
+        .post(format!("{}/register", base_url))
+        .header(EXTENSION_NAME_HEADER, EXTENSION_NAME)
+        .body(r#"{"events":["INVOKE"]}"#)
+        .send()
+        .await?
+        .error_for_status()?;
+    let id = res
+        .headers()
+        .get(EXTENSION_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    Ok(id)
+}
+
+/// 次のイベントを待つ。ランタイムが次の呼び出しを待つ間はブロックされる
+async fn next_event(
+    client: &reqwest::Client, base_url: &str, extension_id: &str,
+) -> Result<NextEvent, Box<dyn std::error::Error + Send + Sync>> {
+    let body = client
+        .get(format!("{}/event/next", base_url))
+        .header(EXTENSION_ID_HEADER, extension_id)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    /// Extensions APIのモック。`/event/next` には `events` を先頭から順に返す(Noneなら500)
+    fn mock_extensions_api(events: Vec<Option<&'static str>>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let events = Arc::new(Mutex::new(events.into_iter()));
+        let paths = Arc::new(Mutex::new(vec![]));
+        let recorded = paths.clone();
+        let make_service = make_service_fn(move |_| {
+            let (events, paths) = (events.clone(), paths.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    let path = req.uri().path().to_string();
+                    paths.lock().unwrap().push(path.clone());
+                    let res = if path.ends_with("/register") {
+                        Response::builder()
+                            .header(EXTENSION_ID_HEADER, "test-extension-id")
+                            .body(Body::empty())
+                    } else {
+                        match events.lock().unwrap().next().flatten() {
+                            Some(event_type) => {
+                                Response::builder().body(Body::from(format!(r#"{{"eventType":"{}"}}"#, event_type)))
+                            }
+                            None => Response::builder()
+                                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                                .body(Body::empty()),
+                        }
+                    };
+                    async move { Ok::<_, Infallible>(res.unwrap()) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}/2020-01-01/extension", server.local_addr());
+        tokio::spawn(server);
+        (url, recorded)
+    }
+
+    #[tokio::test]
+    async fn register_returns_extension_id() {
+        let (url, paths) = mock_extensions_api(vec![]);
+        let id = register(&reqwest::Client::new(), &url).await.unwrap();
+        assert_eq!(id, "test-extension-id");
+        assert_eq!(
+            *paths.lock().unwrap(),
+            vec!["/2020-01-01/extension/register".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_polling_after_errors_until_shutdown() {
+        let (url, paths) = mock_extensions_api(vec![None, Some("INVOKE"), None, Some("SHUTDOWN")]);
+        wait_shutdown(&reqwest::Client::new(), &url, "test-extension-id").await;
+        assert_eq!(paths.lock().unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,130 @@
+//! リクエスト・レスポンスのヘッダのSpanのタグへの反映。
+//!
+//! `DD_TRACE_HEADER_TAGS` で指定したヘッダのみをタグにする(`ヘッダ名:タグ名` をカンマ区切り、タグ名は省略可)。
+//! タグ名を省略した場合は `http.request.headers.<ヘッダ名>` / `http.response.headers.<ヘッダ名>` になる。
+//! RootSpanと外部へのHTTPアクセスのSpanの両方に適用する。
+//!
+//! 認証情報を含むヘッダ(`Authorization` / `Cookie` など)は、指定されていても値を `<redacted>` に置き換える。
+//!
+//! ## Example
+//! `DD_TRACE_HEADER_TAGS=user-agent,x-forwarded-for:client.ip,x-tenant-id,content-type`
+
+use crate::config::CONFIG;
+use crate::helper;
+use lambda_http::http::HeaderMap;
+use tracing::Span;
+
+const REDACTED: &str = "<redacted>";
+/// 常に値を秘匿するヘッダ
+const REDACTED_HEADERS: [&str; 4] = ["authorization", "proxy-authorization", "cookie", "set-cookie"];
+
+/// リクエストヘッダをタグとしてSpanにセットする
+pub(crate) fn set_request_tags(span: &Span, headers: &HeaderMap) {
+    set_tags(span, headers, "request");
+}
+
+/// レスポンスヘッダをタグとしてSpanにセットする
+pub(crate) fn set_response_tags(span: &Span, headers: &HeaderMap) {
+    set_tags(span, headers, "response");
+}
+
+fn set_tags(span: &Span, headers: &HeaderMap, direction: &str) {
+    for (tag, value) in header_tags(headers, direction, &CONFIG.header_tags) {
+        helper::set_tag(span, &tag, &value);
+    }
+}
+
+/// `config` で指定したヘッダを `(タグ名, 値)` にする
+fn header_tags(headers: &HeaderMap, direction: &str, config: &[(String, Option<String>)]) -> Vec<(String, String)> {
+    config
+        .iter()
+        .filter_map(|(header, tag)| {
+            // 同じヘッダが複数ある場合はカンマで連結する
+            let values: Vec<&str> = headers.get_all(header).iter().filter_map(|v| v.to_str().ok()).collect();
+            if values.is_empty() {
+                return None;
+            }
+            let value = if REDACTED_HEADERS.contains(&header.as_str()) {
+                REDACTED.to_string()
+            } else {
+                values.join(",")
+            };
+            let tag = match tag {
+                Some(tag) => tag.clone(),
+                None => format!("http.{}.headers.{}", direction, header),
+            };
+            Some((tag, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(items: &[(&str, Option<&str>)]) -> Vec<(String, Option<String>)> {
+        items
+            .iter()
+            .map(|(header, tag)| (header.to_string(), tag.map(str::to_string)))
+            .collect()
+    }
+
+    fn headers(items: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in items {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn tags_only_configured_headers() {
+        let headers = headers(&[
+            ("user-agent", "curl/8.0"),
+            ("x-forwarded-for", "203.0.113.1"),
+            ("x-tenant-id", "t1"),
+        ]);
+        let config = config(&[
+            ("user-agent", None),
+            ("x-forwarded-for", Some("client.ip")),
+            ("content-type", None),
+        ]);
+        assert_eq!(
+            header_tags(&headers, "request", &config),
+            vec![
+                ("http.request.headers.user-agent".to_string(), "curl/8.0".to_string()),
+                ("client.ip".to_string(), "203.0.113.1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn joins_repeated_headers() {
+        let headers = headers(&[("x-trace-hop", "a"), ("x-trace-hop", "b")]);
+        assert_eq!(
+            header_tags(&headers, "response", &config(&[("x-trace-hop", None)])),
+            vec![("http.response.headers.x-trace-hop".to_string(), "a,b".to_string())]
+        );
+    }
+
+    #[test]
+    fn always_redacts_credential_headers() {
+        let headers = headers(&[
+            ("authorization", "Bearer secret"),
+            ("proxy-authorization", "Basic secret"),
+            ("cookie", "session=secret"),
+            ("set-cookie", "session=secret; HttpOnly"),
+        ]);
+        let config = config(&[
+            ("authorization", None),
+            ("proxy-authorization", None),
+            ("cookie", Some("cookie")),
+            ("set-cookie", None),
+        ]);
+        let tags = header_tags(&headers, "request", &config);
+        assert_eq!(tags.len(), 4);
+        for (tag, value) in tags {
+            assert_eq!(value, REDACTED, "{}", tag);
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! 推論Span(Inferred Span)の情報を組み立てる。
+//!
+//! Lambdaの呼び出し元(API GatewayやDynamoDB Streamsなど)は自身ではトレースを送らないので、
+//! イベントの内容から「呼び出し元で発生した処理」を表すSpanを推論してLambdaのRootSpanの親として送る。
+//! ここではSpanの作成はせず、tracing(独自実装版/otel版)に依存しない情報の組み立てのみ行う。
+//! 実際のSpan作成は各helperの `handle_event_with_trace` で行う。
+
+use aws_lambda_events::event::apigw::ApiGatewayProxyRequestContext;
+use aws_lambda_events::event::dynamodb;
+use aws_lambda_events::event::s3::S3Event;
+use chrono::{DateTime, TimeZone, Utc};
+
+/// 推論Spanを作成するための情報
+#[derive(Debug)]
+pub struct InferredSpan {
+    /// Span名。`aws.dynamodb` など
+    pub name: &'static str,
+    /// Datadog上でのサービス名。Lambda本体とは別のサービスとして表示される(otel_otlpでは反映されない)
+    pub service: String,
+    pub resource: String,
+    /// `span.kind`。同期呼び出し(API Gateway)なら `server`、非同期なら `consumer`
+    pub kind: &'static str,
+    /// 呼び出し元で処理が発生した時刻。Spanの開始時刻になる
+    pub start: DateTime<Utc>,
+    pub tags: Vec<(&'static str, String)>,
+    pub metrics: Vec<(&'static str, f64)>,
+}
+
+impl InferredSpan {
+    /// API Gateway(REST)のリクエストコンテキストから推論Spanを作成する。
+    /// API Gatewayがリクエストを受け付けた時刻(`requestTimeEpoch`)を開始時刻とする事で、Lambdaが呼ばれるまでのレイテンシが見えるようになる。
+    /// サービス名はカスタムドメイン等で区別できるようにドメイン名とする。ステータスコードはレスポンス後に別途セットする。
+    pub fn from_apigw_rest_context(ctx: &ApiGatewayProxyRequestContext) -> Option<Self> {
+        let start = Utc.timestamp_millis_opt(ctx.request_time_epoch).single()?;
+        let domain_name = ctx.domain_name.clone().unwrap_or_default();
+        let method = ctx.http_method.to_string();
+        let resource_path = ctx.resource_path.clone().unwrap_or_default();
+        let path = ctx.path.clone().unwrap_or_default();
+        let service = if domain_name.is_empty() {
+            "aws.apigateway".to_string()
+        } else {
+            domain_name.clone()
+        };
+
+        Some(InferredSpan {
+            name: "aws.apigateway",
+            service,
+            resource: format!("{} {}", method, resource_path),
+            kind: "server",
+            start,
+            tags: vec![
+                ("operation_name", "aws.apigateway.rest".to_string()),
+                ("http.url", format!("{}{}", domain_name, path)),
+                ("http.method", method),
+                ("endpoint", path),
+                ("resource_names", resource_path),
+                ("stage", ctx.stage.clone().unwrap_or_default()),
+                ("apiid", ctx.apiid.clone().unwrap_or_default()),
+                ("domain_name", domain_name),
+                ("request_id", ctx.request_id.clone().unwrap_or_default()),
+                ("_inferred_span.synchronicity", "sync".to_string()),
+                ("_inferred_span.tag_source", "self".to_string()),
+            ],
+            metrics: vec![],
+        })
+    }
+
+    /// DynamoDB Streamsのイベントから推論Spanを作成する。
+    /// バッチ内のレコードはまとめて1つのSpanにする。開始時刻は最も古いレコードの作成時刻。
+    /// レコードが無い場合はNone。
+    pub fn from_dynamodb_event(event: &dynamodb::Event) -> Option<Self> {
+        let first = event.records.first()?;
+        let table_name = first
+            .event_source_arn
+            .as_deref()
+            .and_then(table_name_from_stream_arn)
+            .unwrap_or_default();
+
+        let oldest = event
+            .records
+            .iter()
+            .map(|r| r.change.approximate_creation_date_time)
+            .min()?;
+        // INSERT/MODIFY/REMOVE のうち、バッチ内に含まれるものを出現順に列挙する
+        let mut event_names: Vec<&str> = Vec::new();
+        for r in event.records.iter() {
+            if !event_names.contains(&r.event_name.as_str()) {
+                event_names.push(r.event_name.as_str());
+            }
+        }
+        let oldest_age_ms = (Utc::now() - oldest).num_milliseconds().max(0);
+
+        Some(InferredSpan {
+            name: "aws.dynamodb",
+            service: "aws.dynamodb".to_string(),
+            resource: format!("{} {}", first.event_name, table_name),
+            kind: "consumer",
+            start: oldest,
+            tags: vec![
+                ("operation_name", "aws.dynamodb".to_string()),
+                ("tablename", table_name),
+                ("event_source_arn", first.event_source_arn.clone().unwrap_or_default()),
+                ("event_name", event_names.join(",")),
+                ("approximate_creation_time", oldest.to_rfc3339()),
+                ("_inferred_span.synchronicity", "async".to_string()),
+                ("_inferred_span.tag_source", "self".to_string()),
+            ],
+            metrics: vec![
+                ("records_in_batch", event.records.len() as f64),
+                ("oldest_record_age_ms", oldest_age_ms as f64),
+            ],
+        })
+    }
+
+    /// S3のイベント通知から推論Spanを作成する。
+    /// 1つのイベントに複数のオブジェクトが含まれる場合は、レコード毎にSpanを作成する。
+    pub fn from_s3_event(event: &S3Event) -> Vec<Self> {
+        event
+            .records
+            .iter()
+            .map(|r| {
+                let bucket = r.s3.bucket.name.clone().unwrap_or_default();
+                let key = r.s3.object.key.clone().unwrap_or_default();
+                let event_name = r.event_name.clone().unwrap_or_default();
+                InferredSpan {
+                    name: "aws.s3",
+                    service: "aws.s3".to_string(),
+                    resource: format!("{} {}", event_name, bucket),
+                    kind: "consumer",
+                    start: r.event_time,
+                    tags: vec![
+                        ("operation_name", "aws.s3".to_string()),
+                        ("bucketname", bucket),
+                        ("bucket_arn", r.s3.bucket.arn.clone().unwrap_or_default()),
+                        ("object_key", key),
+                        ("object_size", r.s3.object.size.unwrap_or_default().to_string()),
+                        ("object_etag", r.s3.object.e_tag.clone().unwrap_or_default()),
+                        ("event_name", event_name),
+                        ("_inferred_span.synchronicity", "async".to_string()),
+                        ("_inferred_span.tag_source", "self".to_string()),
+                    ],
+                    metrics: vec![],
+                }
+            })
+            .collect()
+    }
+}
+
+/// `arn:aws:dynamodb:{region}:{account}:table/{table}/stream/{label}` からテーブル名を取り出す
+fn table_name_from_stream_arn(arn: &str) -> Option<String> {
+    let resource = arn.splitn(6, ':').nth(5)?;
+    let mut parts = resource.split('/');
+    match (parts.next(), parts.next()) {
+        (Some("table"), Some(name)) => Some(name.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn tag<'a>(span: &'a InferredSpan, key: &str) -> Option<&'a str> {
+        span.tags.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_str())
+    }
+
+    fn metric(span: &InferredSpan, key: &str) -> Option<f64> {
+        span.metrics.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+    }
+
+    #[test]
+    fn builds_apigateway_span_from_request_context() {
+        let ctx: ApiGatewayProxyRequestContext = serde_json::from_value(json!({
+            "accountId": "123456789012",
+            "resourceId": "abc123",
+            "stage": "prod",
+            "domainName": "api.example.com",
+            "requestId": "c6af9ac6-7b61-11e6-9a41-93e8deadbeef",
+            "protocol": "HTTP/1.1",
+            "identity": { "sourceIp": "127.0.0.1" },
+            "resourcePath": "/orders/{id}",
+            "path": "/prod/orders/1",
+            "httpMethod": "GET",
+            "requestTimeEpoch": 1_700_000_000_123_i64,
+            "apiId": "1234567890"
+        }))
+        .unwrap();
+        let span = InferredSpan::from_apigw_rest_context(&ctx).unwrap();
+
+        assert_eq!(span.name, "aws.apigateway");
+        assert_eq!(span.service, "api.example.com");
+        assert_eq!(span.resource, "GET /orders/{id}");
+        assert_eq!(span.kind, "server");
+        assert_eq!(span.start.timestamp_millis(), 1_700_000_000_123);
+        assert_eq!(tag(&span, "http.url"), Some("api.example.com/prod/orders/1"));
+        assert_eq!(tag(&span, "http.method"), Some("GET"));
+        assert_eq!(tag(&span, "endpoint"), Some("/prod/orders/1"));
+        assert_eq!(tag(&span, "resource_names"), Some("/orders/{id}"));
+        assert_eq!(tag(&span, "stage"), Some("prod"));
+        assert_eq!(tag(&span, "apiid"), Some("1234567890"));
+        assert_eq!(tag(&span, "request_id"), Some("c6af9ac6-7b61-11e6-9a41-93e8deadbeef"));
+        assert_eq!(tag(&span, "_inferred_span.synchronicity"), Some("sync"));
+    }
+
+    #[test]
+    fn uses_default_apigateway_service_without_domain_name() {
+        let ctx: ApiGatewayProxyRequestContext = serde_json::from_value(json!({
+            "identity": {},
+            "resourcePath": "/",
+            "path": "/",
+            "httpMethod": "POST",
+            "requestTimeEpoch": 1_700_000_000_000_i64
+        }))
+        .unwrap();
+        let span = InferredSpan::from_apigw_rest_context(&ctx).unwrap();
+        assert_eq!(span.service, "aws.apigateway");
+        assert_eq!(span.resource, "POST /");
+    }
+
+    fn dynamodb_record(event_name: &str, created_at: DateTime<Utc>) -> serde_json::Value {
+        json!({
+            "eventID": "1",
+            "eventName": event_name,
+            "eventVersion": "1.1",
+            "eventSource": "aws:dynamodb",
+            "awsRegion": "ap-northeast-1",
+            "dynamodb": {
+                "ApproximateCreationDateTime": created_at.timestamp() as f64,
+                "Keys": { "Id": { "N": "101" } },
+                "NewImage": { "Id": { "N": "101" } },
+                "SequenceNumber": "111",
+                "SizeBytes": 26,
+                "StreamViewType": "NEW_AND_OLD_IMAGES"
+            },
+            "eventSourceARN": "arn:aws:dynamodb:ap-northeast-1:123456789012:table/orders/stream/2023-01-01T00:00:00.000"
+        })
+    }
+
+    #[test]
+    fn builds_one_dynamodb_span_for_batch_from_oldest_record() {
+        let now = Utc.timestamp_opt(Utc::now().timestamp(), 0).unwrap();
+        let oldest = now - chrono::Duration::seconds(30);
+        let event: dynamodb::Event = serde_json::from_value(json!({
+            "Records": [
+                dynamodb_record("INSERT", now - chrono::Duration::seconds(10)),
+                dynamodb_record("MODIFY", oldest),
+                dynamodb_record("INSERT", now),
+            ]
+        }))
+        .unwrap();
+        let span = InferredSpan::from_dynamodb_event(&event).unwrap();
+
+        assert_eq!(span.name, "aws.dynamodb");
+        assert_eq!(span.resource, "INSERT orders");
+        assert_eq!(span.kind, "consumer");
+        assert_eq!(span.start, oldest);
+        assert_eq!(tag(&span, "tablename"), Some("orders"));
+        assert_eq!(tag(&span, "event_name"), Some("INSERT,MODIFY"));
+        assert_eq!(
+            tag(&span, "approximate_creation_time"),
+            Some(oldest.to_rfc3339().as_str())
+        );
+        assert_eq!(metric(&span, "records_in_batch"), Some(3.0));
+        let age = metric(&span, "oldest_record_age_ms").unwrap();
+        assert!((30_000.0..60_000.0).contains(&age), "{}", age);
+    }
+
+    #[test]
+    fn skips_empty_dynamodb_batch() {
+        let event: dynamodb::Event = serde_json::from_value(json!({ "Records": [] })).unwrap();
+        assert!(InferredSpan::from_dynamodb_event(&event).is_none());
+    }
+
+    #[test]
+    fn extracts_table_name_from_stream_arn() {
+        assert_eq!(
+            table_name_from_stream_arn(
+                "arn:aws:dynamodb:us-east-1:123456789012:table/orders/stream/2023-01-01T00:00:00.000"
+            )
+            .as_deref(),
+            Some("orders")
+        );
+        assert!(table_name_from_stream_arn("arn:aws:dynamodb:us-east-1:123456789012:global-table/orders").is_none());
+        assert!(table_name_from_stream_arn("not an arn").is_none());
+    }
+
+    fn s3_record(key: &str, event_time: &str) -> serde_json::Value {
+        json!({
+            "eventVersion": "2.1",
+            "eventSource": "aws:s3",
+            "awsRegion": "ap-northeast-1",
+            "eventTime": event_time,
+            "eventName": "ObjectCreated:Put",
+            "userIdentity": { "principalId": "EXAMPLE" },
+            "requestParameters": { "sourceIPAddress": "127.0.0.1" },
+            "responseElements": {
+                "x-amz-request-id": "EXAMPLE123456789",
+                "x-amz-id-2": "EXAMPLE123/5678abcdefghijklambdaisawesome/mnopqrstuvwxyzABCDEFGH"
+            },
+            "s3": {
+                "s3SchemaVersion": "1.0",
+                "configurationId": "testConfigRule",
+                "bucket": {
+                    "name": "uploads",
+                    "ownerIdentity": { "principalId": "EXAMPLE" },
+                    "arn": "arn:aws:s3:::uploads"
+                },
+                "object": { "key": key, "size": 1024, "eTag": "0123456789abcdef", "sequencer": "0A1B2C3D4E5F678901" }
+            }
+        })
+    }
+
+    #[test]
+    fn builds_one_s3_span_per_record() {
+        let event: S3Event = serde_json::from_value(json!({
+            "Records": [
+                s3_record("images/a.png", "2023-01-01T00:00:00.000Z"),
+                s3_record("images/b.png", "2023-01-01T00:00:01.000Z"),
+            ]
+        }))
+        .unwrap();
+        let spans = InferredSpan::from_s3_event(&event);
+
+        assert_eq!(spans.len(), 2);
+        for (span, (key, start)) in spans.iter().zip([
+            ("images/a.png", "2023-01-01T00:00:00Z"),
+            ("images/b.png", "2023-01-01T00:00:01Z"),
+        ]) {
+            assert_eq!(span.name, "aws.s3");
+            assert_eq!(span.resource, "ObjectCreated:Put uploads");
+            assert_eq!(span.kind, "consumer");
+            assert_eq!(span.start, start.parse::<DateTime<Utc>>().unwrap());
+            assert_eq!(tag(span, "bucketname"), Some("uploads"));
+            assert_eq!(tag(span, "bucket_arn"), Some("arn:aws:s3:::uploads"));
+            assert_eq!(tag(span, "object_key"), Some(key));
+            assert_eq!(tag(span, "object_size"), Some("1024"));
+            assert_eq!(tag(span, "object_etag"), Some("0123456789abcdef"));
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! 実行環境(サンドボックス)や関数自体に関する情報。
+
+use crate::config::CONFIG;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// 実行環境内でまだ一度も呼び出されていなければtrue
+static COLD_START: AtomicBool = AtomicBool::new(true);
+
+/// 初期化(main関数)の開始時刻
+static INIT_START: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// 初期化の開始時刻を記録する。初期化時間を計測するため、main関数の冒頭で呼ぶ事。
+pub(crate) fn mark_init_start() {
+    Lazy::force(&INIT_START);
+}
+
+/// 1回の呼び出しに関する情報
+#[derive(Debug)]
+pub(crate) struct Invocation {
+    pub cold_start: bool,
+    started: Instant,
+    /// 拡張メトリクスを送信済みならtrue。タイムアウト直前に送った場合に、ハンドラの終了後に二重に送らないため
+    metrics_submitted: AtomicBool,
+}
+
+impl Invocation {
+    /// 呼び出しの開始時に1回だけ呼ぶ
+    pub fn start() -> Self {
+        Invocation {
+            cold_start: take_cold_start(),
+            started: Instant::now(),
+            metrics_submitted: AtomicBool::new(false),
+        }
+    }
+
+    /// 拡張メトリクスを送信済みにする。既に送信済みだった場合はfalse
+    pub fn mark_metrics_submitted(&self) -> bool {
+        !self.metrics_submitted.swap(true, Ordering::Relaxed)
+    }
+
+    /// 呼び出しの開始からの経過時間
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// コールドスタートの場合、初期化の開始から呼び出しの開始までの時間。
+    /// Provisioned Concurrency・SnapStartでは初期化と最初の呼び出しの間に待機時間が挟まるので、オンデマンドの場合のみ返す
+    pub fn init_duration(&self) -> Option<Duration> {
+        if self.cold_start && initialization_type() == "on-demand" {
+            Some(self.started.saturating_duration_since(*INIT_START))
+        } else {
+            None
+        }
+    }
+}
+
+/// 今回の呼び出しがコールドスタートかどうかを返す。
+/// 実行環境(プロセス)内で最初に呼ばれた時だけtrueになるので、呼び出し毎に1回だけ呼ぶ事。
+fn take_cold_start() -> bool {
+    COLD_START.swap(false, Ordering::Relaxed)
+}
+
+/// 実行環境の初期化タイプ。`on-demand` / `provisioned-concurrency` / `snap-start` のいずれか。
+/// ローカル実行などで環境変数が無い場合は `on-demand` とみなす。
+pub(crate) fn initialization_type() -> String {
+    env::var("AWS_LAMBDA_INITIALIZATION_TYPE").unwrap_or_else(|_| "on-demand".to_string())
+}
+
+/// タイムアウト直前(`DD_APM_FLUSH_DEADLINE_MILLISECONDS` 前)までの残り時間。
+/// 期限が分からない(0)場合や、残り時間がマージン以下の場合はNone
+pub(crate) fn time_until_impending_timeout(ctx: &lambda_runtime::Context) -> Option<Duration> {
+    if ctx.deadline == 0 {
+        return None;
+    }
+    let now = Utc::now().timestamp_millis().max(0) as u64;
+    let limit = ctx.deadline.checked_sub(CONFIG.flush_deadline_margin_ms)?;
+    (limit > now).then(|| Duration::from_millis(limit - now))
+}
+
+/// タイムアウト直前に処理を打ち切った事を表すエラー(`DD_CANCEL_ON_IMPENDING_TIMEOUT=true` の場合のみ)
+#[derive(Debug)]
+pub(crate) struct ImpendingTimeout;
+
+impl Display for ImpendingTimeout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Impending Timeout: the function is about to time out")
+    }
+}
+
+impl std::error::Error for ImpendingTimeout {}
+
+/// 関数自体のメタデータ。Datadogのサーバーレス向けビューで使われるタグとしてRootSpanにセットする。
+#[derive(Debug)]
+pub(crate) struct FunctionMeta {
+    /// バージョン・エイリアスを除いたARN(小文字)
+    pub function_arn: String,
+    pub function_version: String,
+    pub function_name: String,
+    pub region: String,
+    pub account_id: String,
+    pub memory_size: i32,
+}
+
+impl FunctionMeta {
+    /// `arn:aws:lambda:{region}:{account}:function:{name}[:{qualifier}]` 形式のARNと環境変数から組み立てる
+    pub fn from_context(ctx: &lambda_runtime::Context) -> Self {
+        let arn = ctx.invoked_function_arn.as_str();
+        let parts: Vec<&str> = arn.split(':').collect();
+        let function_arn = parts
+            .iter()
+            .take(7)
+            .copied()
+            .collect::<Vec<_>>()
+            .join(":")
+            .to_lowercase();
+        // エイリアスやバージョン付きで呼ばれた場合はそれを、そうでなければ環境変数の値を使う
+        let function_version = parts
+            .get(7)
+            .map(|q| q.to_string())
+            .or_else(|| env::var("AWS_LAMBDA_FUNCTION_VERSION").ok())
+            .unwrap_or_else(|| "$LATEST".to_string());
+        let region = parts
+            .get(3)
+            .map(|r| r.to_string())
+            .or_else(|| env::var("AWS_REGION").ok())
+            .unwrap_or_default();
+        FunctionMeta {
+            function_arn,
+            function_version,
+            function_name: ctx.env_config.function_name.clone(),
+            region,
+            account_id: parts.get(4).map(|a| a.to_string()).unwrap_or_default(),
+            memory_size: ctx.env_config.memory,
+        }
+    }
+
+    /// RootSpanにセットするタグ
+    pub fn tags(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("function_arn", self.function_arn.clone()),
+            ("function_version", self.function_version.clone()),
+            ("functionname", self.function_name.to_lowercase()),
+            ("region", self.region.clone()),
+            ("account_id", self.account_id.clone()),
+            ("memorysize", self.memory_size.to_string()),
+            ("resource_names", self.function_name.clone()),
+            ("_dd.origin", "lambda".to_string()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(arn: &str) -> lambda_runtime::Context {
+        let mut ctx = lambda_runtime::Context::default();
+        ctx.invoked_function_arn = arn.to_string();
+        ctx.env_config.function_name = "My-Function".to_string();
+        ctx.env_config.memory = 512;
+        ctx
+    }
+
+    #[test]
+    fn parses_unqualified_arn() {
+        let meta = FunctionMeta::from_context(&context(
+            "arn:aws:lambda:ap-northeast-1:123456789012:function:My-Function",
+        ));
+        assert_eq!(
+            meta.function_arn,
+            "arn:aws:lambda:ap-northeast-1:123456789012:function:my-function"
+        );
+        assert_eq!(meta.region, "ap-northeast-1");
+        assert_eq!(meta.account_id, "123456789012");
+        assert_eq!(meta.function_name, "My-Function");
+        assert_eq!(meta.memory_size, 512);
+    }
+
+    #[test]
+    fn uses_alias_and_version_of_qualified_arn() {
+        for (arn, version) in [
+            (
+                "arn:aws:lambda:us-east-1:123456789012:function:My-Function:prod",
+                "prod",
+            ),
+            ("arn:aws:lambda:us-east-1:123456789012:function:My-Function:42", "42"),
+            (
+                "arn:aws:lambda:us-east-1:123456789012:function:My-Function:$LATEST",
+                "$LATEST",
+            ),
+        ] {
+            let meta = FunctionMeta::from_context(&context(arn));
+            // ARNのタグからはエイリアス・バージョンを除く
+            assert_eq!(
+                meta.function_arn,
+                "arn:aws:lambda:us-east-1:123456789012:function:my-function"
+            );
+            assert_eq!(meta.function_version, version);
+            assert_eq!(meta.region, "us-east-1");
+        }
+    }
+
+    #[test]
+    fn sets_function_tags() {
+        let tags = FunctionMeta::from_context(&context(
+            "arn:aws:lambda:ap-northeast-1:123456789012:function:My-Function:7",
+        ))
+        .tags();
+        let tag = |key: &str| tags.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_str());
+        assert_eq!(tag("functionname"), Some("my-function"));
+        assert_eq!(tag("function_version"), Some("7"));
+        assert_eq!(tag("resource_names"), Some("My-Function"));
+        assert_eq!(tag("memorysize"), Some("512"));
+        assert_eq!(tag("_dd.origin"), Some("lambda"));
+    }
+}
@@ -1,24 +1,48 @@
 use lambda_http::{run, service_fn, Body, Request, Response};
 use lambda_runtime::Error;
 use std::sync::Mutex;
-use tracing::{error, info, instrument, Level, Span};
+use tracing::{error, info, instrument, Level, Span, Subscriber};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
-use tracing_subscriber::filter::{Filtered, Targets};
+use tracing_subscriber::filter::{Filtered, LevelFilter, Targets};
 use tracing_subscriber::fmt::format::{Format, Json, JsonFields};
 use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{Layer, Registry};
 
+#[cfg(feature = "aws_sdk")]
+mod aws_sdk;
+mod body_tags;
+mod config;
+mod dd_extension;
+mod dogstatsd;
+mod enhanced_metrics;
+mod error_policy;
+mod extensions_api;
+mod header_tags;
 #[cfg_attr(feature = "owned", path = "datadog_helper.rs")]
 #[cfg_attr(not(feature = "owned"), path = "otel_helper.rs")]
 pub mod helper;
+mod inferred_span;
+mod lambda_meta;
+mod metrics;
+mod peer_service;
+mod propagation;
 mod span_processor;
+mod step_functions;
+mod telemetry_api;
+mod tower_client;
+mod trace_extractor;
+mod trace_layer;
+mod traced_client;
+mod url_sanitizer;
 
 fn get_logger() -> Filtered<tracing_subscriber::fmt::Layer<Registry, JsonFields, Format<Json, ()>>, Targets, Registry> {
     let log_filter = Targets::new()
         .with_target("hyper", Level::INFO)
         .with_target("tower", Level::INFO)
         .with_target("h2", Level::INFO)
+        .with_target(metrics::METRIC_TARGET, LevelFilter::OFF)
         .with_default(Level::DEBUG);
 
     tracing_subscriber::fmt::layer()
@@ -30,12 +54,23 @@ fn get_logger() -> Filtered<tracing_subscriber::fmt::Layer<Registry, JsonFields,
         .with_filter(log_filter)
 }
 
+/// カスタムメトリクスをDatadog Lambdaのメトリクス形式でそのまま出力する
+fn get_metric_logger<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_subscriber::fmt::layer()
+        .event_format(metrics::MetricLineFormat)
+        .with_filter(Targets::new().with_target(metrics::METRIC_TARGET, Level::INFO))
+}
+
 /// 独自実装したTracingクレートを使用する
 #[cfg(feature = "owned")]
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     use std::time::Duration;
     println!("--- owned mode --------------");
+    lambda_meta::mark_init_start();
 
     // Datadog Tracing Layer
     // 外部クレートの中で作成されている tracing::span も対象になるので、フィルター設定に注意
@@ -44,12 +79,17 @@ async fn main() -> Result<(), Error> {
         .with_service_name("test-owned")
         .with_filter(Targets::new().with_default(Level::INFO));
 
-    tracing_subscriber::registry().with(get_logger()).with(tracing).init();
+    tracing_subscriber::registry()
+        .with(get_logger())
+        .with(get_metric_logger())
+        .with(tracing)
+        .init();
+    extensions_api::start().await;
 
-    run(service_fn(|req: Request| async {
-        helper::handle_request_with_trace(req, handle_request).await
-    }))
-    .await
+    let service = tower::ServiceBuilder::new()
+        .layer(trace_layer::DatadogTraceLayer)
+        .service(service_fn(handle_request));
+    run(service).await
 }
 
 
@@ -65,6 +105,7 @@ async fn main() -> Result<(), Error> {
     use opentelemetry_api::global;
     use opentelemetry_api::trace::TracerProvider;
     println!("--- otel_dd mode --------------");
+    lambda_meta::mark_init_start();
 
     // tracerに opentelemetry_datadog を使用する
     let builder = opentelemetry_datadog::new_pipeline()
@@ -85,6 +126,15 @@ async fn main() -> Result<(), Error> {
                 Some(String(v)) => v.as_str(),
                 _ => span.name.as_ref(), // `resource` はOtelでは任意だがトレーシングAPIではMust
             }
+        })
+        .with_service_name_mapping(|span, config| {
+            // デフォルトでは全て `with_service_name` の値になるので、推論Spanが別のサービスとして表示されない
+            // Fieldで`service.name`をセットしているSpan(推論Span)ならそれを使用する
+            let key = Key::from_static_str("service.name");
+            match span.attributes.get(&key) {
+                Some(String(v)) => v.as_str(),
+                _ => config.service_name.as_str(),
+            }
         });
     // 独自のSpanProcessorを使用する
     let exporter = builder.build_exporter().unwrap();
@@ -103,17 +153,22 @@ async fn main() -> Result<(), Error> {
         // .with_exception_fields(true) 良くわからん。違いが見えない
         .with_filter(Targets::new().with_default(Level::INFO));
 
-    tracing_subscriber::registry().with(get_logger()).with(tracing).init();
+    tracing_subscriber::registry()
+        .with(get_logger())
+        .with(get_metric_logger())
+        .with(tracing)
+        .init();
+    extensions_api::start().await;
 
     // Propagatorを登録する(が自動でこれが呼ばれたりはしない？？)
     // opentelemetry_datadog にはPropagatorも有り
     // opentelemetry_otlp にはPropagator実装が無いので、独自実装するかopentelemetry_datadogから借用する必要がある
     opentelemetry::global::set_text_map_propagator(DatadogPropagator::default());
 
-    run(service_fn(|req: Request| async {
-        helper::handle_request_with_trace(req, handle_request).await
-    }))
-    .await
+    let service = tower::ServiceBuilder::new()
+        .layer(trace_layer::DatadogTraceLayer)
+        .service(service_fn(handle_request));
+    run(service).await
 }
 
 /// opentelemetry_otlp 使用版
@@ -130,6 +185,7 @@ async fn main() -> Result<(), Error> {
     use opentelemetry_sdk::trace::{RandomIdGenerator, Sampler};
     use opentelemetry_sdk::{trace, Resource};
     println!("--- otel_otlp mode --------------");
+    lambda_meta::mark_init_start();
 
     // tracerに opentelemetry_otlp を使用する以外は差異無し
     let tracer = opentelemetry_otlp::new_pipeline()
@@ -148,26 +204,36 @@ async fn main() -> Result<(), Error> {
                 .with_resource(Resource::new(vec![KeyValue::new("service.name", "test-otel-otlp")])),
         )
         .install_batch(opentelemetry::runtime::Tokio)?;
+    // 呼び出し毎のフラッシュでバッチ内のSpanを送り切るために登録する
+    if let Some(provider) = tracer.provider() {
+        helper::set_tracer_provider(provider);
+    }
 
     let tracing = tracing_opentelemetry::layer()
         .with_tracer(tracer)
         .with_filter(Targets::new().with_default(Level::INFO));
 
-    tracing_subscriber::registry().with(get_logger()).with(tracing).init();
+    tracing_subscriber::registry()
+        .with(get_logger())
+        .with(get_metric_logger())
+        .with(tracing)
+        .init();
+    extensions_api::start().await;
 
     // Propagatorを登録する
     // opentelemetry_otlp にはPropagator実装が無いので、opentelemetry_datadogから借用する
     opentelemetry::global::set_text_map_propagator(DatadogPropagator::default());
 
-    run(service_fn(|req: Request| async {
-        helper::handle_request_with_trace(req, handle_request).await
-    }))
-    .await
+    let service = tower::ServiceBuilder::new()
+        .layer(trace_layer::DatadogTraceLayer)
+        .service(service_fn(handle_request));
+    run(service).await
 }
 
 /// 適当な実装
 pub async fn handle_request(_req: Request) -> Result<Response<Body>, Error> {
     info!("start process request");
+    metrics::send_distribution_metric("sample.request", 1.0, &["handler:handle_request"]);
 
     let resp = match do_something().await {
         Ok(_) => Response::builder()
@@ -190,9 +256,8 @@ pub async fn handle_request(_req: Request) -> Result<Response<Body>, Error> {
 #[instrument]
 async fn do_something() -> Result<(), Error> {
     info!("get from some url.");
-    let c = reqwest::Client::new();
-    let req = c.get(get_url()).build().unwrap();
-    match helper::request_http(&c, req).await {
+    let c = traced_client::new_client(reqwest::Client::new());
+    match c.get(get_url()).send().await {
         Ok(_) => {
             // ... do something with result
             Ok(())
@@ -0,0 +1,71 @@
+//! カスタムメトリクスの送信API。
+//!
+//! 送信経路は実行環境によって自動で切り替える。
+//! - Datadog Lambda Extensionが存在する場合: DogStatsD経由でExtensionに送る
+//! - 存在しない場合(Datadog Forwarderを使う構成): Datadog Lambdaのメトリクス形式(`{"m":..,"v":..,"e":..,"t":[..]}`)でログに出力する
+//!
+//! `DD_FLUSH_TO_LOG=true` の場合は、Extensionの有無に関わらずログに出力する。
+//!
+//! ログ出力は専用のtarget([METRIC_TARGET])を持つtracingのイベントとして行い、main.rsの `get_metric_logger` で
+//! メッセージ(JSON)のみをそのまま1行として出力する。通常のJSONログに混ざるとForwarderがメトリクスとして解釈できないため。
+//!
+//! ## Example
+//! ```
+//! metrics::send_distribution_metric("order.amount", 333.0, &["shop:tokyo"]);
+//! ```
+
+use crate::config::CONFIG;
+use crate::dd_extension;
+use crate::dogstatsd;
+use chrono::Utc;
+use serde_json::json;
+use std::fmt;
+use tracing::{info, Event, Subscriber};
+use tracing_subscriber::field::Visit;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+
+/// メトリクスのログ出力に使うtracingのtarget
+pub(crate) const METRIC_TARGET: &str = "dd_lambda_metric";
+
+/// ディストリビューションメトリクスを送る。`tags` は `key:value` 形式。
+/// Extension経由の場合は呼び出しの終了時にまとめて送られる。
+pub fn send_distribution_metric(name: &str, value: f64, tags: &[&str]) {
+    if dd_extension::is_available() && !CONFIG.flush_to_log {
+        dogstatsd::client().distribution(name, value, tags);
+    } else {
+        let line = json!({
+            "m": name,
+            "v": value,
+            "e": Utc::now().timestamp(),
+            "t": tags,
+        });
+        info!(target: METRIC_TARGET, "{}", line);
+    }
+}
+
+/// メトリクスのイベントのメッセージのみを出力するフォーマッタ
+pub(crate) struct MetricLineFormat;
+
+impl<S, N> FormatEvent<S, N> for MetricLineFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, _ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut visitor = MessageVisitor(String::new());
+        event.record(&mut visitor);
+        writeln!(writer, "{}", visitor.0)
+    }
+}
+
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
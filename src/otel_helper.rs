@@ -1,17 +1,42 @@
 //! ヘルパー関数群
 //!
 
+use crate::body_tags;
+use crate::config::CONFIG;
+use crate::dd_extension;
+use crate::dogstatsd;
+use crate::enhanced_metrics;
+use crate::error_policy;
+use crate::error_policy::ResponseInfo;
+use crate::header_tags;
+use crate::inferred_span::InferredSpan;
+use crate::lambda_meta;
+use crate::lambda_meta::{FunctionMeta, ImpendingTimeout, Invocation};
+use crate::peer_service;
+use crate::span_processor;
+use crate::telemetry_api;
+use crate::trace_extractor::TraceExtractor;
+use crate::url_sanitizer;
+use aws_lambda_events::event::dynamodb;
+use aws_lambda_events::event::s3::S3Event;
+use lambda_http::http::HeaderMap;
 use lambda_http::request::RequestContext;
-use lambda_http::{Body, Request, RequestExt};
-use lambda_runtime::Error;
-use opentelemetry_api::trace::TraceContextExt;
-use opentelemetry_api::Context;
+use lambda_http::{Request, RequestExt};
+use lambda_runtime::{Context as LambdaContext, Error, LambdaEvent};
+use once_cell::sync::OnceCell;
+use opentelemetry_api::trace::{Span as _, SpanBuilder, SpanId, TraceContextExt};
+use opentelemetry_api::{Context, Key, Value};
 use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_sdk::trace::TracerProvider;
 use rand::Rng;
+use serde_json::json;
 use std::collections::HashMap;
 use std::future::Future;
-use tracing::info_span;
-use tracing_opentelemetry::OpenTelemetrySpanExt;
+use std::time::SystemTime;
+use tracing::{info_span, warn, Span};
+use tracing_opentelemetry::{OpenTelemetrySpanExt, OtelData};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
 
 const DD_TRACE_ID_HEADER: &str = "x-datadog-trace-id";
 const DD_PARENT_ID_HEADER: &str = "x-datadog-parent-id";
@@ -22,6 +47,32 @@ fn gen_trace_id() -> u64 {
     rng.gen::<u64>()
 }
 
+/// リクエストヘッダをPropagatorに渡して、トレースID等を保持したContextを作成する。
+/// Propagatorはmain.rsの冒頭でsetしたDatadogPropagator(のはず
+fn extract_context(headers: &HeaderMap) -> Context {
+    // ヘッダにトレースID等が含まれない場合、Propagatorは無効なContextを返す実装になっており、結果的にトレースが送られないので
+    // その場合は新規採番して処理させる
+    if headers.contains_key(DD_TRACE_ID_HEADER) {
+        opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+    } else {
+        new_context()
+    }
+}
+
+/// トレースIDを新規採番したContextを作成する
+fn new_context() -> Context {
+    context_from_ids(gen_trace_id(), 0)
+}
+
+/// 指定したトレースID・親SpanIDを持つContextを作成する
+fn context_from_ids(trace_id: u64, parent_id: u64) -> Context {
+    let mut map = HashMap::new();
+    map.insert(DD_TRACE_ID_HEADER.to_string(), trace_id.to_string());
+    map.insert(DD_PARENT_ID_HEADER.to_string(), parent_id.to_string());
+    map.insert(DD_SAMPLING_PRIORITY_HEADER.to_string(), "1".to_string()); // SamplingPriority.AutoKeep
+    opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(&map))
+}
+
 /// ContextからトレースIDを取得する。
 /// Contextは適切な取得の仕方をしないと期待した結果にならないので注意。
 /// 基本的には例のように `Span::current()` から辿る。
@@ -44,6 +95,9 @@ pub fn get_trace_id_from(ctx: &Context) -> u64 {
 /// - 処理の起点となるSpanを作成し、付属情報を色々セットする。
 /// - 処理結果をSpanに反映する。
 ///
+/// `DD_UNIVERSAL_INSTRUMENTATION=true` でDatadog Lambda Extensionがある場合は、RootSpanの作成をExtensionに任せる。
+/// 詳細は [handle_request_with_universal_instrumentation] 参照。
+///
 /// ## Example
 /// ```
 /// async fn handle_request(req: Request) -> Result<(), Error> {
@@ -61,75 +115,551 @@ pub fn get_trace_id_from(ctx: &Context) -> u64 {
 ///
 ///     Ok(())
 /// ```
-pub async fn handle_request_with_trace<Fut>(
+pub async fn handle_request_with_trace<B, Fut>(
     req: Request, f: impl FnOnce(Request) -> Fut,
-) -> Result<lambda_http::Response<Body>, Error>
+) -> Result<lambda_http::Response<B>, Error>
 where
-    Fut: Future<Output = Result<lambda_http::Response<Body>, Error>>,
+    Fut: Future<Output = Result<lambda_http::Response<B>, Error>>,
+    B: 'static,
 {
+    if dd_extension::use_universal_instrumentation() {
+        return handle_request_with_universal_instrumentation(req, f).await;
+    }
+    let invocation = Invocation::start();
     // リクエストヘッダをPropagatorに渡して、トレースID等をContextに保持する
-    // Propagatorはmain.rsの冒頭でsetしたDatadogPropagator(のはず
-    let ctx = opentelemetry::global::get_text_map_propagator(|propagator| {
-        // ヘッダにトレースID等が含まれない場合、Propagatorは無効なContextを返す実装になっており、結果的にトレースが送られないので
-        // その場合は新規採番して処理させる
-        if req.headers().contains_key("x-datadog-trace-id") {
-            propagator.extract(&HeaderExtractor(req.headers()))
-        } else {
-            let mut map = HashMap::new();
-            map.insert(DD_TRACE_ID_HEADER.to_string(), gen_trace_id().to_string());
-            map.insert(DD_PARENT_ID_HEADER.to_string(), "0".to_string());
-            map.insert(DD_SAMPLING_PRIORITY_HEADER.to_string(), "1".to_string()); // SamplingPriority.AutoKeep
-            propagator.extract(&map)
-        }
-    });
+    let ctx = extract_context(req.headers());
 
     let lambda_ctx = req.lambda_context();
-    let request_id = &lambda_ctx.request_id;
     let apigw_ctx = req.request_context();
     let RequestContext::ApiGatewayV1(rest_api_ctx) = apigw_ctx;
+    // API Gatewayの推論Spanを作成する(DD_TRACE_MANAGED_SERVICES=falseなら作成しない)
+    let apigw_span = InferredSpan::from_apigw_rest_context(&rest_api_ctx)
+        .filter(|_| CONFIG.managed_services)
+        .map(|info| {
+            let span = inferred_span(&info);
+            span.set_parent(ctx.clone());
+            span
+        });
     let path = rest_api_ctx.path.unwrap();
     // RootSpanを作成する
-    let root_span = info_span!(
-        "handle_request_root",
-        // Logとのマージ用にトレースIDを出力しておく。Log毎にトレースIDを
-        trace_id = get_trace_id_from(&ctx),
-        // 以下任意でDatadogに渡したい値をセットする。以下は一例。
-        // 後から `span.record(..)` で更新するケースでも、このタイミングで宣言しておく必要がある。
-        // otel.で始まるフィールドは特別に処理されたりしてる。詳細は実装(tracing-opentelemetry-0.19.0/src/layer.rs)参照
-        request_id,
-        resource = path,
-        otel.kind = "server",
-        otel.status_code = "unset", // ok/error/else=unset。okをセットするのは必須ではない
-        error.message = None::<String>
+    let root_span = root_span(
+        &ctx,
+        &lambda_ctx,
+        &invocation,
+        &lambda_ctx.env_config.function_name,
+        "server",
     );
+    set_tag(&root_span, "http.url", &path);
+    header_tags::set_request_tags(&root_span, req.headers());
+    body_tags::set_request_body_tags(&root_span, req.body().as_ref());
 
     // lambda-runtimeを使用していると、この時点で`Lambda runtime invoke`というSpanが作成済みだが、
     // ここで作成しているRootSpanと被るので、それは無視してこのSpanにParentを設定する。
     // ちなみに無視したそのSpanもDatadogには送られる(がトレースからは孤立したSpanになる。
-    root_span.set_parent(ctx);
+    // API Gatewayの推論Spanがあれば、そちらを親にする。
+    match &apigw_span {
+        Some(parent) => root_span.set_parent(parent.context()),
+        None => root_span.set_parent(ctx),
+    }
     let _enter = root_span.enter();
 
-    let handle_request_result = f(req).await;
-    match handle_request_result {
+    let parents = apigw_span.iter().collect::<Vec<_>>();
+    let handle_request_result = guard_timeout(&root_span, &parents, &lambda_ctx, Some(&invocation), f(req)).await;
+    let (result, is_error) = match handle_request_result {
         Ok(ret) => {
             root_span.record("http.status_code", ret.status().as_u16());
-            if ret.status().as_u16() >= 500 {
+            header_tags::set_response_tags(&root_span, ret.headers());
+            if let Some(apigw_span) = &apigw_span {
+                set_tag(apigw_span, "http.status_code", &ret.status().as_u16().to_string());
+            }
+            let res = ResponseInfo {
+                status: ret.status().as_u16(),
+                headers: ret.headers(),
+                body: body_tags::buffered_body(ret.body()),
+            };
+            let is_error = error_policy::is_server_error(&res);
+            if is_error {
+                body_tags::set_response_body_tags(&root_span, res.body);
                 // エラー時には、OtelSpanのStatusをErrorにしたい(そうすればDatadog上でもフラグが立つ)
                 // OtelのSpanにはそれらのためのメソッドが用意されてるが、tracing経由だとアクセスできないので、
                 // 従来通り tracing::Span.record() する
                 // なお、span内からエラーレベルのログを出力した場合も、SpanStatusはErrorになる。
                 root_span.record("otel.status_code", "error");
+                if let Some(apigw_span) = &apigw_span {
+                    apigw_span.record("otel.status_code", "error");
+                }
             }
-            Ok(ret)
+            (Ok(ret), is_error)
         }
         Err(err) => {
             root_span.record("otel.status_code", "error");
             // エラーメッセージを回収する(ログとマージするなら冗長かもしれないが)
             // tracing-otelでは `exception.message` という名前を指定しているようだが、それだとDatadog側で認識されない
             root_span.record("error.message", err.to_string());
-            Err(err)
+            (Err(err), true)
         }
+    };
+    enhanced_metrics::submit(&lambda_ctx, &invocation, is_error);
+    dogstatsd::client().flush();
+    // コールドスタートなら、Telemetry APIから届いている初期化時間をセットする
+    for (key, value) in telemetry_api::take_init_metrics(invocation.cold_start) {
+        set_metric(&root_span, key, value);
+    }
+    // Spanを閉じてから、Extensionにフラッシュを依頼する
+    drop(_enter);
+    telemetry_api::defer_root_span(&lambda_ctx, &invocation, &root_span);
+    drop(root_span);
+    drop(apigw_span);
+    flush_to_extension().await;
+    result
+}
+
+/// Datadog Lambda Extensionのuniversal instrumentationを使う場合の [handle_request_with_trace]。
+/// RootSpan(`aws.lambda`)・推論Span・拡張メトリクスはExtensionが作成するので、ここではそのRootSpanの子としてハンドラのSpanを作成する。
+/// - イベントを `/lambda/start-invocation` に送り、Extensionが決めたトレースIDを受け取る。
+/// - ExtensionのRootSpanのIDを採番して、ハンドラのSpanの親にする。
+/// - 処理結果とRootSpanのIDを `/lambda/end-invocation` に送る。
+async fn handle_request_with_universal_instrumentation<B, Fut>(
+    req: Request, f: impl FnOnce(Request) -> Fut,
+) -> Result<lambda_http::Response<B>, Error>
+where
+    Fut: Future<Output = Result<lambda_http::Response<B>, Error>>,
+    B: 'static,
+{
+    let lambda_ctx = req.lambda_context();
+    let payload = dd_extension::request_payload(&req);
+    // Extensionに繋がらなかった場合でも、こちらのトレースは送れるようにヘッダから引き継ぐか新規採番する
+    let trace_id = match dd_extension::start_invocation(&lambda_ctx.request_id, &payload).await {
+        Some(tc) => tc.trace_id,
+        None => get_trace_id_from(&extract_context(req.headers())),
+    };
+    let execution_span_id = gen_trace_id();
+    let ctx = context_from_ids(trace_id, execution_span_id);
+
+    let span = info_span!(
+        "aws.lambda.handler",
+        trace_id,
+        request_id = lambda_ctx.request_id.as_str(),
+        resource = lambda_ctx.env_config.function_name.as_str(),
+        otel.kind = "internal",
+        otel.status_code = "unset",
+        http.status_code = tracing::field::Empty,
+        error.message = None::<String>,
+    );
+    span.set_parent(ctx);
+    let _enter = span.enter();
+
+    header_tags::set_request_tags(&span, req.headers());
+    body_tags::set_request_body_tags(&span, req.body().as_ref());
+    let result = guard_timeout(&span, &[], &lambda_ctx, None, f(req)).await;
+    let (response, error) = match &result {
+        Ok(ret) => {
+            let status = ret.status().as_u16();
+            span.record("http.status_code", status);
+            header_tags::set_response_tags(&span, ret.headers());
+            let res = ResponseInfo {
+                status,
+                headers: ret.headers(),
+                body: body_tags::buffered_body(ret.body()),
+            };
+            let error = error_policy::is_server_error(&res).then(|| format!("http status {}", status));
+            if error.is_some() {
+                body_tags::set_response_body_tags(&span, res.body);
+            }
+            (json!({ "statusCode": status }), error)
+        }
+        Err(err) => (json!({}), Some(err.to_string())),
+    };
+    if let Some(msg) = &error {
+        span.record("otel.status_code", "error");
+        span.record("error.message", msg.as_str());
+    }
+    dogstatsd::client().flush();
+    drop(_enter);
+    drop(span);
+    // end-invocationより前に、ハンドラのSpanをExtensionに届けておく
+    span_processor::flush().await;
+    force_flush_tracer_provider().await;
+    dd_extension::end_invocation(trace_id, execution_span_id, &response, error.as_deref()).await;
+    dd_extension::flush().await;
+    result
+}
+
+/// 任意のイベント(`LambdaEvent<T>`)を処理する際に挿入するヘルパー関数。
+/// HTTP以外(`lambda_runtime::service_fn` で型付きのイベントを受け取る場合)で [handle_request_with_trace] の代わりに使う。
+/// トレース情報の取り出し方やRootSpanの内容は、イベントの型に実装された [TraceExtractor] に従う。
+/// - トレース情報が取り出せればそれを引き継ぎ、無ければ新規採番する。
+/// - 推論Spanがあればそれを作成し、先頭の推論Spanの子としてRootSpanを作成する。
+/// - 処理結果をSpanに反映する。
+///
+/// ## Example
+/// ```
+/// async fn handle_event(event: LambdaEvent<dynamodb::Event>) -> Result<(), Error> {
+///     ...
+/// }
+///
+///     run(service_fn(|event: LambdaEvent<dynamodb::Event>| async {
+///         helper::handle_event_with_trace(event, handle_event).await
+///     })).await?;
+/// ```
+pub async fn handle_event_with_trace<T, R, Fut>(
+    event: LambdaEvent<T>, f: impl FnOnce(LambdaEvent<T>) -> Fut,
+) -> Result<R, Error>
+where
+    T: TraceExtractor,
+    Fut: Future<Output = Result<R, Error>>,
+{
+    let invocation = Invocation::start();
+    let ctx = match event.payload.trace_context() {
+        Some(tc) => context_from_ids(tc.trace_id, tc.parent_id),
+        None => new_context(),
+    };
+    let inferred_spans = if CONFIG.managed_services {
+        event.payload.inferred_spans()
+    } else {
+        vec![]
+    };
+    let inferred_spans: Vec<Span> = inferred_spans
+        .iter()
+        .map(|info| {
+            let span = inferred_span(info);
+            span.set_parent(ctx.clone());
+            span
+        })
+        .collect();
+
+    let resource = event
+        .payload
+        .resource()
+        .unwrap_or_else(|| event.context.env_config.function_name.clone());
+    let root_span = root_span(&ctx, &event.context, &invocation, &resource, event.payload.span_kind());
+    match inferred_spans.first() {
+        Some(parent) => root_span.set_parent(parent.context()),
+        None => root_span.set_parent(ctx),
+    }
+    for (key, value) in event.payload.tags().iter() {
+        set_tag(&root_span, key, value);
+    }
+    let _enter = root_span.enter();
+
+    let lambda_ctx = event.context.clone();
+    let parents = inferred_spans.iter().collect::<Vec<_>>();
+    let result = guard_timeout(&root_span, &parents, &lambda_ctx, Some(&invocation), f(event)).await;
+    if let Err(err) = &result {
+        root_span.record("otel.status_code", "error");
+        root_span.record("error.message", err.to_string());
+    }
+    enhanced_metrics::submit(&lambda_ctx, &invocation, result.is_err());
+    dogstatsd::client().flush();
+    // コールドスタートなら、Telemetry APIから届いている初期化時間をセットする
+    for (key, value) in telemetry_api::take_init_metrics(invocation.cold_start) {
+        set_metric(&root_span, key, value);
+    }
+    // Spanを閉じてから、Extensionにフラッシュを依頼する
+    drop(_enter);
+    telemetry_api::defer_root_span(&lambda_ctx, &invocation, &root_span);
+    drop(root_span);
+    drop(inferred_spans);
+    flush_to_extension().await;
+    result
+}
+
+/// DynamoDB Streamsのイベントを処理する際に挿入するヘルパー関数。
+/// `aws.dynamodb` の推論Spanを作成し、その子としてRootSpanを作成する。
+/// 推論Spanにはテーブル名やイベント名(INSERT/MODIFY/REMOVE)、バッチ内のレコード数などがセットされる。
+pub async fn handle_dynamodb_event_with_trace<R, Fut>(
+    event: LambdaEvent<dynamodb::Event>, f: impl FnOnce(LambdaEvent<dynamodb::Event>) -> Fut,
+) -> Result<R, Error>
+where
+    Fut: Future<Output = Result<R, Error>>,
+{
+    handle_event_with_trace(event, f).await
+}
+
+/// S3のイベント通知を処理する際に挿入するヘルパー関数。
+/// レコード毎に `aws.s3` の推論Spanを作成し、先頭のSpanの子としてRootSpanを作成する。
+/// 推論Spanにはバケット名、オブジェクトキー、サイズ、イベント名がセットされる。
+pub async fn handle_s3_event_with_trace<R, Fut>(
+    event: LambdaEvent<S3Event>, f: impl FnOnce(LambdaEvent<S3Event>) -> Fut,
+) -> Result<R, Error>
+where
+    Fut: Future<Output = Result<R, Error>>,
+{
+    handle_event_with_trace(event, f).await
+}
+
+/// Step Functionsのタスクとして呼ばれた際に挿入するヘルパー関数。
+/// ペイロードにStep FunctionsのContextオブジェクト(`Execution`/`State`/`StateMachine`)が含まれていれば、
+/// 実行ARN等からトレースIDと親SpanIDを決定的に算出する。これにより1つの実行内の各Lambdaが同じトレースに繋がる。
+/// 含まれていなければ新規採番する。
+pub async fn handle_step_functions_event_with_trace<R, Fut>(
+    event: LambdaEvent<serde_json::Value>, f: impl FnOnce(LambdaEvent<serde_json::Value>) -> Fut,
+) -> Result<R, Error>
+where
+    Fut: Future<Output = Result<R, Error>>,
+{
+    handle_event_with_trace(event, f).await
+}
+
+/// `install_batch` 等で作成されたTracerProvider。フラッシュ時にバッファ内のSpanを送り切るために使う
+static TRACER_PROVIDER: OnceCell<TracerProvider> = OnceCell::new();
+
+/// SpanProcessorを使わない構成(otel_otlpの `install_batch` など)で、フラッシュ時にExportを待つためにTracerProviderを登録する。
+/// 登録しないと、バッチ内のSpanが送られる前にExtensionへのフラッシュが行われて取りこぼす。
+///
+/// ## Example
+/// ```
+/// let tracer = opentelemetry_otlp::new_pipeline().tracing().install_batch(opentelemetry::runtime::Tokio)?;
+/// if let Some(provider) = tracer.provider() {
+///     helper::set_tracer_provider(provider);
+/// }
+/// ```
+pub fn set_tracer_provider(provider: TracerProvider) {
+    let _ = TRACER_PROVIDER.set(provider);
+}
+
+/// 登録されたTracerProviderのバッファ内のSpanを全てExportする。
+/// BatchSpanProcessorの `force_flush` はExportの完了までスレッドをブロックするので、ブロッキング用のスレッドで呼ぶ
+async fn force_flush_tracer_provider() {
+    let Some(provider) = TRACER_PROVIDER.get().cloned() else {
+        return;
+    };
+    match tokio::task::spawn_blocking(move || provider.force_flush()).await {
+        Ok(results) => results
+            .into_iter()
+            .filter_map(Result::err)
+            .for_each(|e| warn!("failed to flush tracer provider: {:?}", e)),
+        Err(e) => warn!("failed to flush tracer provider: {:?}", e),
+    }
+}
+
+/// 終了したSpanが全てExportされるのを待ってから、Datadog Lambda Extensionにフラッシュを依頼する。
+/// SpanProcessor(otel_dd)と、登録されたTracerProvider(otel_otlp)の両方を待つ。
+async fn flush_to_extension() {
+    if !dd_extension::is_available() {
+        return;
+    }
+    span_processor::flush().await;
+    force_flush_tracer_provider().await;
+    dd_extension::flush().await;
+}
+
+/// 送信中のSpanとメトリクスを全て送り切る。実行環境のShutdown時に呼ぶ
+pub(crate) async fn flush_all() {
+    dogstatsd::client().flush();
+    span_processor::flush().await;
+    force_flush_tracer_provider().await;
+    dd_extension::flush().await;
+}
+
+/// Lambdaのタイムアウト直前になっても処理が終わらない場合、RootSpanをエラーにして、その時点で終了したものとしてフラッシュする。
+/// RootSpanには `error.type=Impending Timeout` がセットされる。タイミングは `DD_APM_FLUSH_DEADLINE_MILLISECONDS` (デフォルト100ms)で調整する。
+/// `parents` はRootSpanの親の推論Span。RootSpanと合わせてその時点でExportする。
+/// `invocation` を渡した場合は、拡張メトリクス(`invocations` / `timeouts` など)もその時点で送る。
+/// ハンドラの処理は打ち切らずにそのまま待つ(途中で止めると副作用が中途半端に残りうるため)。
+/// それ以降の処理は `aws.lambda.detached` Span(Export済みのRootSpanの子)に記録される。
+/// `DD_CANCEL_ON_IMPENDING_TIMEOUT=true` の場合は処理を打ち切ってエラーとして返し、通常通りSpanをクローズしてExportさせる。
+/// 期限が取得できない場合や、既にマージンを切っている場合は何もしない。
+async fn guard_timeout<R>(
+    span: &Span, parents: &[&Span], lambda_ctx: &LambdaContext, invocation: Option<&Invocation>,
+    fut: impl Future<Output = Result<R, Error>>,
+) -> Result<R, Error> {
+    let Some(remaining) = lambda_meta::time_until_impending_timeout(lambda_ctx) else {
+        return fut.await;
+    };
+    tokio::pin!(fut);
+    tokio::select! {
+        result = &mut fut => return result,
+        _ = tokio::time::sleep(remaining) => {}
+    }
+    set_tag(span, "error.type", "Impending Timeout");
+    span.record("otel.status_code", "error");
+    if let Some(invocation) = invocation {
+        enhanced_metrics::submit_impending_timeout(lambda_ctx, invocation);
+    }
+    if CONFIG.cancel_on_impending_timeout {
+        return Err(ImpendingTimeout.into());
+    }
+    // Lambdaに実行環境ごと止められるとSpanがクローズされないので、ここでExportしておく
+    export_now(span, true);
+    parents.iter().for_each(|parent| export_now(parent, false));
+    dogstatsd::client().flush();
+    flush_to_extension().await;
+    fut.await
+}
+
+/// Spanをその時点で終了したものとしてExportする。元のSpanはクローズされてもExportされないようにする。
+/// `detach` がtrueの場合は、以降の記録や子Spanの受け皿として、Export済みのSpanの子の `aws.lambda.detached` Spanに差し替える
+/// (こちらは元のSpanのクローズ時にExportされる)。
+fn export_now(span: &Span, detach: bool) {
+    let tracer = opentelemetry::global::tracer("rust-datadog");
+    span.with_subscriber(|(id, dispatch)| {
+        let Some(span_ref) = dispatch
+            .downcast_ref::<Registry>()
+            .and_then(|registry| registry.span(id))
+        else {
+            return;
+        };
+        let mut extensions = span_ref.extensions_mut();
+        let Some(data) = extensions.remove::<OtelData>() else {
+            return;
+        };
+        let resource = data
+            .builder
+            .attributes
+            .as_ref()
+            .and_then(|attributes| attributes.get(&Key::from_static_str("resource")).cloned());
+        let mut exported = data.builder.start_with_context(&tracer, &data.parent_cx);
+        let span_context = exported.span_context().clone();
+        exported.end();
+        if detach {
+            let mut builder = SpanBuilder::from_name("aws.lambda.detached");
+            builder.trace_id = Some(span_context.trace_id());
+            builder.span_id = Some(SpanId::from(gen_trace_id().to_be_bytes()));
+            builder.start_time = Some(SystemTime::now());
+            builder.attributes = resource.map(|r| [(Key::from_static_str("resource"), r)].into_iter().collect());
+            extensions.insert(OtelData {
+                parent_cx: Context::new().with_remote_span_context(span_context),
+                builder,
+            });
+        }
+    });
+}
+
+/// RootSpanを作成する。
+/// Datadogのサーバーレス向けのビューで扱えるように、Span名は `aws.lambda` とし関数のメタデータをタグにセットする。
+fn root_span(
+    ctx: &Context, lambda_ctx: &LambdaContext, invocation: &Invocation, resource: &str, kind: &'static str,
+) -> Span {
+    let span = info_span!(
+        "aws.lambda",
+        // Logとのマージ用にトレースIDを出力しておく。Log毎にトレースIDを
+        trace_id = get_trace_id_from(ctx),
+        // 以下任意でDatadogに渡したい値をセットする。以下は一例。
+        // 後から `span.record(..)` で更新するケースでも、このタイミングで宣言しておく必要がある。
+        // otel.で始まるフィールドは特別に処理されたりしてる。詳細は実装(tracing-opentelemetry-0.19.0/src/layer.rs)参照
+        request_id = lambda_ctx.request_id.as_str(),
+        resource,
+        otel.kind = kind,
+        otel.status_code = "unset", // ok/error/else=unset。okをセットするのは必須ではない
+        http.status_code = tracing::field::Empty,
+        error.message = None::<String>,
+        // コールドスタートのレイテンシを区別できるように、初回呼び出しかどうかと初期化タイプをセットしておく
+        cold_start = invocation.cold_start,
+        init_type = lambda_meta::initialization_type(),
+    );
+    for (key, value) in FunctionMeta::from_context(lambda_ctx).tags().iter() {
+        set_tag(&span, key, value);
     }
+    span
+}
+
+/// 推論Spanを作成する。
+/// Span名や開始時刻はtracingのマクロでは動的に指定できないので、作成後にOtelDataを直接書き換える。
+fn inferred_span(info: &InferredSpan) -> Span {
+    let span = info_span!(
+        "inferred_span",
+        resource = info.resource.as_str(),
+        // otel_ddでは、main.rsの `with_service_name_mapping` でこの属性をサービス名にしている。
+        // otel_otlpではサービス名はResource単位で決まるので、推論SpanもLambda本体と同じサービスになる
+        service.name = info.service.as_str(),
+        otel.kind = info.kind,
+        otel.status_code = "unset",
+    );
+    with_otel_data(&span, |data| {
+        data.builder.name = info.name.into();
+        data.builder.start_time = Some(info.start.into());
+    });
+    for (key, value) in info.tags.iter() {
+        set_tag(&span, key, value);
+    }
+    for (key, value) in info.metrics.iter() {
+        set_metric(&span, key, *value);
+    }
+    span
+}
+
+/// クローズ後にタグを追加してからExportするSpan。Telemetry APIのレコードが届くまでRootSpanを預かるのに使う
+pub(crate) struct PendingSpan {
+    data: OtelData,
+    end_time: SystemTime,
+}
+
+impl PendingSpan {
+    /// Spanの内容を取り出して預かる。終了時刻は呼び出した時点とする。以降、元のSpanはクローズされてもExportしない
+    pub fn take(span: &Span) -> Option<Self> {
+        span.with_subscriber(|(id, dispatch)| {
+            let span_ref = dispatch.downcast_ref::<Registry>()?.span(id)?;
+            let data = span_ref.extensions_mut().remove::<OtelData>()?;
+            Some(PendingSpan {
+                data,
+                end_time: SystemTime::now(),
+            })
+        })
+        .flatten()
+    }
+
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        self.insert(Key::from(key.to_string()), Value::from(value.to_string()));
+    }
+
+    pub fn set_metric(&mut self, key: &str, value: f64) {
+        self.insert(Key::from(key.to_string()), Value::F64(value));
+    }
+
+    fn insert(&mut self, key: Key, value: Value) {
+        self.data
+            .builder
+            .attributes
+            .get_or_insert_with(Default::default)
+            .insert(key, value);
+    }
+
+    /// Exportする
+    pub fn export(self) {
+        let tracer = opentelemetry::global::tracer("rust-datadog");
+        let PendingSpan { data, end_time } = self;
+        data.builder
+            .start_with_context(&tracer, &data.parent_cx)
+            .end_with_timestamp(end_time);
+    }
+}
+
+/// Spanに文字列のタグをセットする。
+/// tracingのフィールドは作成時に静的に宣言しておく必要があるので、キーが動的に決まるものはこちらを使う。
+pub(crate) fn set_tag(span: &Span, key: &str, value: &str) {
+    let (key, value) = (Key::from(key.to_string()), Value::from(value.to_string()));
+    with_otel_data(span, |data| {
+        data.builder
+            .attributes
+            .get_or_insert_with(Default::default)
+            .insert(key, value);
+    });
+}
+
+/// Spanに数値のメトリクスをセットする。
+pub(crate) fn set_metric(span: &Span, key: &str, value: f64) {
+    let key = Key::from(key.to_string());
+    with_otel_data(span, |data| {
+        data.builder
+            .attributes
+            .get_or_insert_with(Default::default)
+            .insert(key, Value::F64(value));
+    });
+}
+
+/// tracingのSpanに紐づくOtelData(tracing-opentelemetryが保持しているSpanの情報)を操作する。
+fn with_otel_data(span: &Span, f: impl FnOnce(&mut OtelData)) {
+    span.with_subscriber(|(id, dispatch)| {
+        if let Some(span_ref) = dispatch
+            .downcast_ref::<Registry>()
+            .and_then(|registry| registry.span(id))
+        {
+            if let Some(data) = span_ref.extensions_mut().get_mut::<OtelData>() {
+                f(data);
+            }
+        }
+    });
 }
 
 /// 外部へのHTTPアクセスする際に差し込むヘルパー関数
@@ -139,6 +669,7 @@ where
 /// - レスポンスをSpanに反映する。
 ///
 /// ここではクライアントとして `Reqwest` を使う実装となっている。
+/// 全てのリクエストに自動で適用したい場合は [crate::traced_client::TracedClient] を使う。
 ///
 /// ## Example
 /// ```
@@ -149,40 +680,186 @@ where
 pub async fn request_http(
     client: &reqwest::Client, mut req: reqwest::Request,
 ) -> Result<reqwest::Response, reqwest::Error> {
+    let span = client_span("reqwest.http", req.method().as_str(), req.url().as_str());
+    header_tags::set_request_tags(&span, req.headers());
+    let _enter = span.enter();
+
+    // リクエストヘッダにトレーシング用ヘッダを追加する
+    // アクセス先もDatadogに対応していればトレースが繋がる想定
+    inject_headers(&span, req.headers_mut());
+
+    match client.execute(req).await {
+        Ok(ret) => {
+            record_client_response(&span, ret.status().as_u16(), ret.headers());
+            Ok(ret)
+        }
+        Err(err) => {
+            record_client_error(&span, &err.to_string());
+            Err(err)
+        }
+    }
+}
+
+/// 外部へのHTTPアクセスを表すSpanを作成する。URLはサニタイズしてからセットする(url_sanitizer参照)
+/// 接続先を表すタグ(`peer.service` など)もセットする。
+/// Span名はクライアントの種類毎に呼び出し側で指定する(Reqwestなら `reqwest.http`、towerのクライアントなら `http.request`)
+pub(crate) fn client_span(name: &str, method: &str, url: &str) -> Span {
+    let sanitized = url_sanitizer::sanitize(url);
     let span = info_span!(
-        "reqwest.http",
-        resource = req.url().to_string(),
-        http.url = req.url().to_string(),
-        http.method = req.method().to_string(),
+        "http.client",
+        resource = format!("{} {}", method, sanitized.resource),
+        http.url = sanitized.url,
+        http.method = method,
         http.status_code = tracing::field::Empty,
         otel.kind = "client",
         otel.status_code = "unset",
         error.message = None::<String>,
     );
-    let _enter = span.enter();
+    with_otel_data(&span, |data| data.builder.name = name.to_string().into());
+    for (key, value) in peer_service::peer_tags(&sanitized).iter() {
+        set_tag(&span, key, value);
+    }
+    span
+}
 
-    // リクエストヘッダにトレーシング用ヘッダを追加する
-    // アクセス先もDatadogに対応していればトレースが繋がる想定
-    let headers = req.headers_mut();
+/// リクエストヘッダに、指定したSpanを親とするトレーシング用ヘッダを挿入する
+pub(crate) fn inject_headers(span: &Span, headers: &mut HeaderMap) {
     opentelemetry::global::get_text_map_propagator(|propagator| {
         let mut injector = HeaderInjector(headers);
         propagator.inject_context(&span.context(), &mut injector)
     });
+}
 
-    match client.execute(req).await {
-        Ok(ret) => {
-            span.record("http.status_code", ret.status().as_u16());
-            // レスポンスが5xxならエラーフラグを立てる。
-            // 200でも実質エラーという処理系の場合は適宜対応する事
-            if ret.status().as_u16() >= 500 {
-                span.record("otel.status_code", "error");
-            }
-            Ok(ret)
+/// 指定したSpanを親とするトレーシング用ヘッダをMapとして取得する。
+/// メッセージ(SQSのメッセージ属性など)にトレース情報を埋め込む際に使う
+pub(crate) fn trace_headers(span: &Span) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    opentelemetry::global::get_text_map_propagator(|propagator| propagator.inject_context(&span.context(), &mut map));
+    map
+}
+
+/// レスポンスのステータスコードをSpanに反映する。
+/// エラーとするかは [error_policy] に従う(デフォルトは5xx)。レスポンスヘッダは [header_tags] に従ってタグにする
+pub(crate) fn record_client_response(span: &Span, status: u16, headers: &HeaderMap) {
+    span.record("http.status_code", status);
+    header_tags::set_response_tags(span, headers);
+    let res = ResponseInfo {
+        status,
+        headers,
+        body: None,
+    };
+    if error_policy::is_client_error(&res) {
+        span.record("otel.status_code", "error");
+    }
+}
+
+/// AWS SDKの呼び出しを表すSpanを作成する。Span名は `aws.<service>` (`aws.dynamodb` など)。
+/// ステータス・エラーは [record_client_response] / [record_client_error] で反映する。
+#[cfg(feature = "aws_sdk")]
+pub(crate) fn aws_client_span(service: &str, operation: &str) -> Span {
+    let span = info_span!(
+        "aws_sdk",
+        resource = format!("{}.{}", service, operation),
+        aws.service = service,
+        aws.operation = operation,
+        http.status_code = tracing::field::Empty,
+        otel.kind = "client",
+        otel.status_code = "unset",
+        error.message = None::<String>,
+    );
+    with_otel_data(&span, |data| {
+        data.builder.name = format!("aws.{}", service).into();
+    });
+    span
+}
+
+/// 通信エラーをSpanに反映する
+pub(crate) fn record_client_error(span: &Span, msg: &str) {
+    span.record("otel.status_code", "error");
+    span.record("error.message", msg);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_api::trace::{Status, TracerProvider as _};
+    use opentelemetry_sdk::export::trace::SpanData;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// 終了したSpanを記録するだけのSpanProcessor
+    #[derive(Debug)]
+    struct CaptureProcessor(Arc<Mutex<Vec<SpanData>>>);
+
+    impl opentelemetry_sdk::trace::SpanProcessor for CaptureProcessor {
+        fn on_start(&self, _span: &mut opentelemetry_sdk::trace::Span, _cx: &Context) {}
+
+        fn on_end(&self, span: SpanData) {
+            self.0.lock().unwrap().push(span);
         }
-        Err(err) => {
-            span.record("otel.status_code", "error");
-            span.record("error.message", err.to_string());
-            Err(err)
+
+        fn force_flush(&self) -> opentelemetry_api::trace::TraceResult<()> {
+            Ok(())
+        }
+
+        fn shutdown(&mut self) -> opentelemetry_api::trace::TraceResult<()> {
+            Ok(())
         }
     }
+
+    /// 指定した時間後にタイムアウト直前になるContext
+    fn context_timing_out_in(after: Duration) -> LambdaContext {
+        let mut ctx = LambdaContext::default();
+        ctx.deadline =
+            chrono::Utc::now().timestamp_millis() as u64 + CONFIG.flush_deadline_margin_ms + after.as_millis() as u64;
+        ctx
+    }
+
+    #[tokio::test]
+    async fn exports_flagged_root_span_on_impending_timeout() {
+        let spans = Arc::new(Mutex::new(vec![]));
+        let provider = TracerProvider::builder()
+            .with_span_processor(CaptureProcessor(spans.clone()))
+            .build();
+        let tracer = provider.tracer("test");
+        // export_now はグローバルのTracerProviderを使う
+        let _ = opentelemetry::global::set_tracer_provider(provider);
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let span = info_span!("aws.lambda", resource = "test", otel.status_code = "unset");
+        let enter = span.enter();
+        let exported = spans.clone();
+        let ctx = context_timing_out_in(Duration::from_millis(20));
+        let result = guard_timeout(&span, &[], &ctx, None, async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            // ハンドラの処理が終わる前にExportされている
+            Ok::<_, Error>(
+                exported
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|s| s.name.to_string())
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .await;
+        assert_eq!(result.unwrap(), vec!["aws.lambda".to_string()]);
+
+        // 元のSpanのクローズ時は、差し替えた aws.lambda.detached のみExportされる
+        drop(enter);
+        drop(span);
+        let spans = spans.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        let (root, detached) = (&spans[0], &spans[1]);
+        assert!(matches!(root.status, Status::Error { .. }));
+        assert_eq!(
+            root.attributes.get(&Key::from_static_str("error.type")),
+            Some(&Value::from("Impending Timeout"))
+        );
+        assert_eq!(detached.name, "aws.lambda.detached");
+        assert_eq!(detached.parent_span_id, root.span_context.span_id());
+        assert_eq!(detached.span_context.trace_id(), root.span_context.trace_id());
+    }
 }
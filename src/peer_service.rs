@@ -0,0 +1,81 @@
+//! クライアントSpanの接続先を表すタグ。
+//!
+//! Datadogのサービスマップで下流の依存関係を描画するには、接続先を表すタグ(`peer.service` など)が必要。
+//! URLから以下を組み立てる。
+//! - `peer.hostname` / `out.host`: ホスト名
+//! - `network.destination.port`: ポート
+//! - `http.route`: 正規化したパス
+//! - `peer.service`: デフォルトはホスト名。`DD_TRACE_PEER_SERVICE_MAPPING` (`元の値:新しい値` をカンマ区切り)で置き換えられる
+//!
+//! 置き換えた場合は、元の値を `_dd.peer.service.remapped_from` に残す。
+
+use crate::config::CONFIG;
+use crate::url_sanitizer::SanitizedUrl;
+use std::collections::HashMap;
+
+/// クライアントSpanにセットする接続先のタグ。ホスト名が無い(パスのみの)場合は `http.route` のみ
+pub(crate) fn peer_tags(url: &SanitizedUrl) -> Vec<(&'static str, String)> {
+    peer_tags_with_mapping(url, &CONFIG.peer_service_mapping)
+}
+
+fn peer_tags_with_mapping(url: &SanitizedUrl, mapping: &HashMap<String, String>) -> Vec<(&'static str, String)> {
+    let mut tags = vec![("http.route", url.route.clone())];
+    if url.host.is_empty() {
+        return tags;
+    }
+    tags.push(("peer.hostname", url.host.clone()));
+    tags.push(("out.host", url.host.clone()));
+    if let Some(port) = url.port {
+        tags.push(("network.destination.port", port.to_string()));
+    }
+    match mapping.get(&url.host) {
+        Some(mapped) => {
+            tags.push(("peer.service", mapped.clone()));
+            tags.push(("_dd.peer.service.remapped_from", url.host.clone()));
+        }
+        None => tags.push(("peer.service", url.host.clone())),
+    }
+    tags.push(("_dd.peer.service.source", "out.host".to_string()));
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::url_sanitizer::sanitize;
+
+    fn tag<'a>(tags: &'a [(&'static str, String)], key: &str) -> Option<&'a str> {
+        tags.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn uses_host_as_peer_service_by_default() {
+        let tags = peer_tags_with_mapping(&sanitize("https://api.example.com:8443/users/123"), &HashMap::new());
+        assert_eq!(tag(&tags, "peer.service"), Some("api.example.com"));
+        assert_eq!(tag(&tags, "peer.hostname"), Some("api.example.com"));
+        assert_eq!(tag(&tags, "out.host"), Some("api.example.com"));
+        assert_eq!(tag(&tags, "network.destination.port"), Some("8443"));
+        assert_eq!(tag(&tags, "_dd.peer.service.source"), Some("out.host"));
+        assert_eq!(tag(&tags, "_dd.peer.service.remapped_from"), None);
+    }
+
+    #[test]
+    fn remaps_peer_service() {
+        let mapping = HashMap::from([("api.example.com".to_string(), "billing-api".to_string())]);
+        let tags = peer_tags_with_mapping(&sanitize("https://api.example.com/invoices"), &mapping);
+        assert_eq!(tag(&tags, "peer.service"), Some("billing-api"));
+        assert_eq!(tag(&tags, "_dd.peer.service.remapped_from"), Some("api.example.com"));
+        assert_eq!(tag(&tags, "peer.hostname"), Some("api.example.com"));
+
+        // 対応するホスト名が無ければ置き換えない
+        let tags = peer_tags_with_mapping(&sanitize("https://other.example.com/invoices"), &mapping);
+        assert_eq!(tag(&tags, "peer.service"), Some("other.example.com"));
+    }
+
+    #[test]
+    fn sets_only_route_without_host() {
+        let tags = peer_tags_with_mapping(&sanitize("/users/123"), &HashMap::new());
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].0, "http.route");
+    }
+}
@@ -0,0 +1,150 @@
+//! 送信するメッセージへのトレース情報の埋め込み。
+//!
+//! HTTPリクエストならヘッダにトレース情報を挿入できるが、SQS/SNS/EventBridge/Kinesisに送るメッセージには
+//! 自前で埋め込まないと、受信側のLambdaのトレースが新規に始まってしまう。
+//! Datadog公式のライブラリと同じ形式で埋め込むので、受信側がDatadog公式のライブラリでもこのクレートでも繋がる。
+//! - SQS/SNS: `_datadog` という名前のメッセージ属性(String)にJSONで入れる
+//! - EventBridge: `detail._datadog` に入れる
+//! - Kinesis: レコードのデータ(JSONオブジェクト)の `_datadog` に入れる
+//!
+//! 各サービスのサイズ制限を超える場合や、SQS/SNSのメッセージ属性が上限(10個)に達している場合は埋め込まない。
+//! AWS SDKを使っている場合は `aws_sdk` featureの [DatadogInterceptor](crate::aws_sdk::DatadogInterceptor) が自動で埋め込む。
+//!
+//! ## Example
+//! ```
+//! // Source/DetailType/Resourcesなど、Detail以外のエントリのサイズ
+//! let other_entry_bytes = source.len() + detail_type.len();
+//! let detail = propagation::inject_eventbridge_detail(&detail, &tracing::Span::current(), other_entry_bytes)
+//!     .unwrap_or(detail);
+//! ```
+
+use crate::helper;
+use serde_json::Value;
+use tracing::Span;
+
+/// 埋め込むメッセージ属性・フィールドの名前
+pub const DATADOG_KEY: &str = "_datadog";
+/// SQS/SNSのメッセージ属性の上限数
+pub const MAX_MESSAGE_ATTRIBUTES: usize = 10;
+/// SQS/SNSのメッセージの最大サイズ(本文とメッセージ属性の合計)
+pub const SQS_SNS_MAX_MESSAGE_BYTES: usize = 262_144;
+/// EventBridgeのイベント(エントリ)の最大サイズ
+pub const EVENTBRIDGE_MAX_ENTRY_BYTES: usize = 262_144;
+/// Kinesisのレコードのデータの最大サイズ
+pub const KINESIS_MAX_RECORD_BYTES: usize = 1_048_576;
+
+/// 指定したSpanを親とするトレース情報をJSONオブジェクトとして取得する
+pub fn trace_context(span: &Span) -> Value {
+    let map: serde_json::Map<String, Value> = helper::trace_headers(span)
+        .into_iter()
+        .map(|(k, v)| (k, Value::String(v)))
+        .collect();
+    Value::Object(map)
+}
+
+/// SQS/SNSの `_datadog` メッセージ属性(DataTypeはString)に入れる値を作成する。
+/// `message_bytes` は本文と既存のメッセージ属性を合わせたサイズ、`attribute_count` は既存のメッセージ属性の数。
+/// 上限を超える場合はNone。
+pub fn sqs_sns_attribute(span: &Span, message_bytes: usize, attribute_count: usize) -> Option<String> {
+    if attribute_count >= MAX_MESSAGE_ATTRIBUTES {
+        return None;
+    }
+    let value = trace_context(span).to_string();
+    (message_bytes + sqs_sns_attribute_bytes(&value) <= SQS_SNS_MAX_MESSAGE_BYTES).then_some(value)
+}
+
+/// [sqs_sns_attribute] で作成した値を入れた `_datadog` メッセージ属性のサイズ。
+/// 属性のサイズは 名前 + DataType + 値 で計算される
+pub fn sqs_sns_attribute_bytes(value: &str) -> usize {
+    DATADOG_KEY.len() + "String".len() + value.len()
+}
+
+/// EventBridgeのイベントのDetail(JSON文字列)の `_datadog` にトレース情報を入れる。
+/// `other_entry_bytes` はDetail以外(Source/DetailType/Resourcesなど)のサイズ。
+/// DetailがJSONオブジェクトでない場合や、上限を超える場合はNone。
+pub fn inject_eventbridge_detail(detail: &str, span: &Span, other_entry_bytes: usize) -> Option<String> {
+    let injected = inject_json_object(detail.as_bytes(), span)?;
+    let injected = String::from_utf8(injected).ok()?;
+    (other_entry_bytes + injected.len() <= EVENTBRIDGE_MAX_ENTRY_BYTES).then_some(injected)
+}
+
+/// Kinesisのレコードのデータ(JSONオブジェクト)の `_datadog` にトレース情報を入れる。
+/// データがJSONオブジェクトでない場合や、上限を超える場合はNone。
+pub fn inject_kinesis_data(data: &[u8], span: &Span) -> Option<Vec<u8>> {
+    let injected = inject_json_object(data, span)?;
+    (injected.len() <= KINESIS_MAX_RECORD_BYTES).then_some(injected)
+}
+
+fn inject_json_object(json: &[u8], span: &Span) -> Option<Vec<u8>> {
+    let mut value: Value = serde_json::from_slice(json).ok()?;
+    value
+        .as_object_mut()?
+        .insert(DATADOG_KEY.to_string(), trace_context(span));
+    serde_json::to_vec(&value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_sqs_sns_attribute_at_max_attributes() {
+        let span = Span::none();
+        assert!(sqs_sns_attribute(&span, 0, MAX_MESSAGE_ATTRIBUTES - 1).is_some());
+        assert!(sqs_sns_attribute(&span, 0, MAX_MESSAGE_ATTRIBUTES).is_none());
+    }
+
+    #[test]
+    fn limits_sqs_sns_message_size_including_attribute() {
+        let span = Span::none();
+        let value = sqs_sns_attribute(&span, 0, 0).unwrap();
+        let max_message_bytes = SQS_SNS_MAX_MESSAGE_BYTES - sqs_sns_attribute_bytes(&value);
+        assert_eq!(sqs_sns_attribute(&span, max_message_bytes, 0), Some(value));
+        assert!(sqs_sns_attribute(&span, max_message_bytes + 1, 0).is_none());
+    }
+
+    #[test]
+    fn injects_eventbridge_detail_into_json_object() {
+        let injected = inject_eventbridge_detail(r#"{"id":1}"#, &Span::none(), 0).unwrap();
+        let detail: Value = serde_json::from_str(&injected).unwrap();
+        assert_eq!(detail["id"], 1);
+        assert!(detail[DATADOG_KEY].is_object());
+
+        assert!(inject_eventbridge_detail("[1,2]", &Span::none(), 0).is_none());
+        assert!(inject_eventbridge_detail("not json", &Span::none(), 0).is_none());
+    }
+
+    #[test]
+    fn limits_eventbridge_entry_size_including_other_entries() {
+        let span = Span::none();
+        let injected = inject_eventbridge_detail("{}", &span, 0).unwrap();
+        let max_other_bytes = EVENTBRIDGE_MAX_ENTRY_BYTES - injected.len();
+        assert_eq!(inject_eventbridge_detail("{}", &span, max_other_bytes), Some(injected));
+        assert!(inject_eventbridge_detail("{}", &span, max_other_bytes + 1).is_none());
+    }
+
+    #[test]
+    fn injects_kinesis_data_only_into_json_object() {
+        let injected = inject_kinesis_data(br#"{"id":1}"#, &Span::none()).unwrap();
+        let data: Value = serde_json::from_slice(&injected).unwrap();
+        assert_eq!(data["id"], 1);
+        assert!(data[DATADOG_KEY].is_object());
+
+        for data in [&b"[1,2]"[..], b"\"text\"", b"42", b"plain text", &[0xff, 0xfe]] {
+            assert!(inject_kinesis_data(data, &Span::none()).is_none());
+        }
+    }
+
+    #[test]
+    fn limits_kinesis_record_size() {
+        let span = Span::none();
+        let overhead = inject_kinesis_data(br#"{"a":""}"#, &span).unwrap().len() - r#"{"a":""}"#.len();
+        let fits = serde_json::json!({ "a": "a".repeat(KINESIS_MAX_RECORD_BYTES - 8 - overhead) }).to_string();
+        assert_eq!(
+            inject_kinesis_data(fits.as_bytes(), &span).unwrap().len(),
+            KINESIS_MAX_RECORD_BYTES
+        );
+        let exceeds = serde_json::json!({ "a": "a".repeat(KINESIS_MAX_RECORD_BYTES - 7 - overhead) }).to_string();
+        assert!(inject_kinesis_data(exceeds.as_bytes(), &span).is_none());
+    }
+}
@@ -1,23 +1,66 @@
 use opentelemetry_api::Context;
-use opentelemetry_api::trace::TraceResult;
+use opentelemetry_api::trace::{TraceError, TraceResult};
 use opentelemetry_sdk::export::trace::{SpanData, SpanExporter};
 use opentelemetry_sdk::trace;
-use tokio::sync::mpsc::Sender;
+use once_cell::sync::OnceCell;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
 use tracing::error;
 
+/// 最初に作成したSpanProcessorへの送信口。[flush] で使う。
+/// SpanProcessorは実行環境内で1つだけ作成する想定で、2つ目以降はここには登録されない([trace::SpanProcessor::force_flush] ではフラッシュできる)
+static SENDER: OnceCell<UnboundedSender<Message>> = OnceCell::new();
+
+/// バックグラウンドのタスクに渡すメッセージ
+#[derive(Debug)]
+enum Message {
+    Export(SpanData),
+    /// それまでに受け取ったSpanを全てExportし終えたら通知する
+    Flush(oneshot::Sender<()>),
+}
+
+/// それまでに終了したSpanが全てExportされるまで待つ。
+/// Datadog Lambda Extensionにフラッシュを依頼する前に呼ぶ(Extensionに届く前に依頼しても意味がないので)。
+/// SpanProcessorを使っていない場合は何もしない。
+pub(crate) async fn flush() {
+    let Some(tx) = SENDER.get() else {
+        return;
+    };
+    let Ok(ack_rx) = request_flush(tx) else {
+        return;
+    };
+    let _ = ack_rx.await;
+}
+
+/// バックグラウンドのタスクにフラッシュを依頼する。Export済みになると返り値のReceiverに通知される
+fn request_flush(tx: &UnboundedSender<Message>) -> TraceResult<oneshot::Receiver<()>> {
+    let (ack_tx, ack_rx) = oneshot::channel();
+    tx.send(Message::Flush(ack_tx))
+        .map_err(|e| TraceError::from(format!("SpanProcessor failed to send channel: {:?}", e)))?;
+    Ok(ack_rx)
+}
+
 /// 何もしないでExporterに渡すだけのProcessor
 /// デフォルトで用意されている [SimpleSpanProcessor](opentelemetry_sdk::trace::SimpleSpanProcessor) がTokioに対応してないので自作した。
 /// なお、同じくデフォルトで用意されてる [BatchSpanProcessor](opentelemetry_sdk::trace::BatchSpanProcessor) もLambdaではNG(リクエスト処理が終わると実行環境はフリーズされるので、遅延処理は期待通りに動かない)
 #[derive(Debug)]
 pub(crate) struct SpanProcessor {
-    tx: Sender<SpanData>,
+    tx: UnboundedSender<Message>,
 }
 
 impl SpanProcessor {
     pub(crate) fn new(mut exporter: Box<dyn SpanExporter>) -> Self {
-        let (tx, mut rx) = tokio::sync::mpsc::channel::<SpanData>(32);
+        // on_endの順番通りに処理してflushで待てるように、unboundedで同期的に積む
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Message>();
         tokio::spawn(async move {
-            while let Some(span) = rx.recv().await {
+            while let Some(message) = rx.recv().await {
+                let span = match message {
+                    Message::Export(span) => span,
+                    Message::Flush(ack) => {
+                        let _ = ack.send(());
+                        continue;
+                    }
+                };
                 println!(
                     "SpanProcessor received: {{ name: '{}', trace_id: {}, span_id: {}, parent_id: {}, ... }}",
                     span.name,
@@ -31,6 +74,7 @@ impl SpanProcessor {
             }
         });
 
+        let _ = SENDER.set(tx.clone());
         SpanProcessor { tx }
     }
 }
@@ -42,20 +86,22 @@ impl trace::SpanProcessor for SpanProcessor {
         if !span.span_context.is_sampled() {
             return;
         }
-        let tx = self.tx.clone();
-        tokio::spawn(async move {
-            if let Err(e) = tx.send(span).await {
-                error!("SpanProcessor failed to send channel: {:?}", e);
-            }
-        });
+        if let Err(e) = self.tx.send(Message::Export(span)) {
+            error!("SpanProcessor failed to send channel: {:?}", e);
+        }
     }
 
+    /// それまでに受け取ったSpanが全てExportされるまで、スレッドをブロックして待つ。
+    /// 非同期のコンテキストから呼ぶとパニックするので、ブロッキング用のスレッドから呼ぶ事。非同期のコンテキストでは [flush] を使う。
     fn force_flush(&self) -> TraceResult<()> {
-        Ok(())
+        request_flush(&self.tx)?
+            .blocking_recv()
+            .map_err(|e| TraceError::Other(Box::new(e)))
     }
 
     /// 特になにもしない。
     /// そもそもLambdaが一定時間呼び出しがなくてShutdownする際、アプリコードは一切呼ばれないので、実装する意味も無い。
+    /// Shutdown時の後始末が必要なら `DD_SHUTDOWN_FLUSH=true` にする(extensions_api参照)。
     fn shutdown(&mut self) -> TraceResult<()> {
         Ok(())
     }
@@ -0,0 +1,126 @@
+//! Step Functionsのタスクとして呼ばれた場合のトレース情報の取り扱い。
+//!
+//! Step FunctionsはトレースヘッダをLambdaに渡してくれないので、ステートマシン側で
+//! `"Payload.$": "States.JsonMerge($$, $, false)"` のようにContextオブジェクトをペイロードに含めてもらう前提。
+//! 実行ARNなどからトレースID・親SpanIDを決定的に算出する事で、1つの実行内の各Lambdaを同じトレースに繋げる。
+//! 算出方法はDatadog公式のライブラリ(datadog-lambda-js等)と同じ。
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// ペイロードに含まれるStep FunctionsのContextオブジェクト
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct StepFunctionsContext {
+    execution: Execution,
+    state: State,
+    state_machine: StateMachine,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Execution {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct State {
+    name: String,
+    entered_time: String,
+    #[serde(default)]
+    retry_count: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct StateMachine {
+    id: String,
+    name: String,
+}
+
+impl StepFunctionsContext {
+    /// ペイロードからContextオブジェクトを取り出す。含まれない場合はNone
+    pub fn from_payload(payload: &serde_json::Value) -> Option<Self> {
+        StepFunctionsContext::deserialize(payload).ok()
+    }
+
+    /// 実行ARNから算出したトレースID。同じ実行であれば全てのステップで同じ値になる
+    pub fn trace_id(&self) -> u64 {
+        hash_to_id(&self.execution.id, HashPart::Lower)
+    }
+
+    /// 実行ARN・ステート名・ステートに入った時刻から算出した親SpanID
+    pub fn parent_id(&self) -> u64 {
+        hash_to_id(
+            &format!("{}#{}#{}", self.execution.id, self.state.name, self.state.entered_time),
+            HashPart::Upper,
+        )
+    }
+
+    /// RootSpanにセットするタグ
+    pub fn tags(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("step_function.execution_id", self.execution.id.clone()),
+            ("step_function.execution_name", self.execution.name.clone()),
+            ("step_function.state_name", self.state.name.clone()),
+            ("step_function.state_entered_time", self.state.entered_time.clone()),
+            ("step_function.state_retry_count", self.state.retry_count.to_string()),
+            ("step_function.state_machine_arn", self.state_machine.id.clone()),
+            ("step_function.state_machine_name", self.state_machine.name.clone()),
+        ]
+    }
+}
+
+/// SHA-256のどの64bitを使うか。公式のライブラリではトレースIDは下位(8..16バイト目)、親SpanIDは先頭(0..8バイト目)を使う
+#[derive(Clone, Copy)]
+enum HashPart {
+    Upper,
+    Lower,
+}
+
+/// SHA-256の64bitを、最上位bitを落としてIDとして使う
+fn hash_to_id(s: &str, part: HashPart) -> u64 {
+    let digest = Sha256::digest(s.as_bytes());
+    let range = match part {
+        HashPart::Upper => 0..8,
+        HashPart::Lower => 8..16,
+    };
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[range]);
+    u64::from_be_bytes(bytes) & 0x7fff_ffff_ffff_ffff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const EXECUTION_ARN: &str = "arn:aws:states:sa-east-1:425362996713:execution:abhinav-activity-state-machine:72a7ca3e-901c-41bb-b5a3-5f279b92a316";
+
+    fn context() -> StepFunctionsContext {
+        StepFunctionsContext::from_payload(&json!({
+            "Execution": { "Id": EXECUTION_ARN, "Name": "72a7ca3e-901c-41bb-b5a3-5f279b92a316" },
+            "State": { "Name": "step-one", "EnteredTime": "2022-12-08T21:08:19.224Z", "RetryCount": 0 },
+            "StateMachine": { "Id": "arn:aws:states:sa-east-1:425362996713:stateMachine:abhinav-activity-state-machine", "Name": "abhinav-activity-state-machine" },
+        }))
+        .unwrap()
+    }
+
+    // 公式のライブラリの算出方法(SHA-256を2進数の文字列にして、トレースIDは64..128bit目、親SpanIDは0..64bit目の最上位bitを0にする)で求めた値
+    #[test]
+    fn trace_id_uses_lower_64_bits() {
+        assert_eq!(context().trace_id(), 435175499815315247);
+    }
+
+    #[test]
+    fn parent_id_uses_upper_64_bits() {
+        assert_eq!(context().parent_id(), 8460413332402608748);
+    }
+
+    #[test]
+    fn payload_without_context() {
+        assert!(StepFunctionsContext::from_payload(&json!({ "foo": "bar" })).is_none());
+    }
+}
@@ -0,0 +1,296 @@
+//! Lambda Telemetry APIの購読。
+//!
+//! Lambdaのプラットフォームが計測した値(課金対象の処理時間、最大メモリ使用量、初期化時間など)を
+//! `platform.initReport` / `platform.runtimeDone` / `platform.report` のレコードとして受け取る。
+//! Telemetry APIの購読には内部Extensionとしての登録が必要なので、[crate::extensions_api] から開始する。
+//! レコードはローカルに立てたHTTPサーバで受け取る。
+//!
+//! 各レコードが届くタイミングによって、扱いを分けている。
+//! - `platform.initReport`: 初回の呼び出しの開始時に届くので、コールドスタートの呼び出しのRootSpanにメトリクスとしてセットする
+//! - `platform.runtimeDone` / `platform.report`: ハンドラの処理が終わった後に届く。
+//!   そのため、購読している場合はRootSpanをクローズ時に送らずにリクエストID毎に預かっておき([defer_root_span])、
+//!   レコードの値をタグ・メトリクスとしてセットしてから送る。`platform.report` が届いた時点で送信して、Extensionにフラッシュを依頼する。
+//!   `platform.report` の値は拡張メトリクス(`aws.lambda.enhanced.*`)としても送る。タイムアウト・メモリ不足の検知もこのレコードで行う
+//!
+//! `platform.report` が次の呼び出しの終了までに届かなかったRootSpanは、その時点でそのまま送る。
+//!
+//! `DD_TELEMETRY_API=true` の場合のみ購読する。受信ポートは `DD_TELEMETRY_LISTENER_PORT` (デフォルト4243)。
+
+use crate::config::CONFIG;
+use crate::enhanced_metrics;
+use crate::helper;
+use crate::helper::PendingSpan;
+use crate::lambda_meta::{FunctionMeta, Invocation};
+use crate::metrics;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use lambda_runtime::Context as LambdaContext;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tracing::{info, warn, Span};
+
+const EXTENSION_ID_HEADER: &str = "Lambda-Extension-Identifier";
+/// `platform.initReport` から取り出したメトリクス。コールドスタートの呼び出しのRootSpanにセットする
+static INIT_METRICS: Mutex<Vec<(&'static str, f64)>> = Mutex::new(Vec::new());
+/// Telemetry APIを購読できたらtrue。購読していない場合はRootSpanを預からない
+static SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+/// `platform.report` を待っている呼び出し。キーはリクエストID
+static PENDING: Lazy<Mutex<HashMap<String, PendingInvocation>>> = Lazy::new(Default::default);
+
+/// `platform.report` を待っている呼び出しの情報
+struct PendingInvocation {
+    /// RootSpan。レコードの値をセットしてから送る
+    span: Option<PendingSpan>,
+    /// 拡張メトリクスのタグ。[enhanced_metrics] と同じもの
+    tags: Vec<String>,
+}
+
+/// Telemetry APIから送られるイベント
+#[derive(Deserialize, Debug)]
+struct TelemetryEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    #[serde(default)]
+    record: serde_json::Value,
+}
+
+/// `platform.*` のレコード。必要な項目のみ
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct PlatformRecord {
+    request_id: Option<String>,
+    /// `success` / `error` / `failure` / `timeout`
+    status: Option<String>,
+    /// メモリ不足の場合は `Runtime.OutOfMemory`
+    error_type: Option<String>,
+    #[serde(default)]
+    metrics: PlatformMetrics,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct PlatformMetrics {
+    duration_ms: Option<f64>,
+    billed_duration_ms: Option<f64>,
+    #[serde(rename = "memorySizeMB")]
+    memory_size_mb: Option<f64>,
+    #[serde(rename = "maxMemoryUsedMB")]
+    max_memory_used_mb: Option<f64>,
+    produced_bytes: Option<f64>,
+}
+
+/// レコードを受け取るHTTPサーバを立ち上げてから、Telemetry APIを購読する。
+/// `runtime_api` は `AWS_LAMBDA_RUNTIME_API` の値。
+pub(crate) async fn start(client: &reqwest::Client, runtime_api: &str, extension_id: &str) {
+    if !CONFIG.telemetry_api {
+        return;
+    }
+    let addr = SocketAddr::from(([0, 0, 0, 0], CONFIG.telemetry_listener_port));
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(receive)) })),
+        Err(e) => {
+            warn!("failed to bind telemetry listener: {:?}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("telemetry listener stopped: {:?}", e);
+        }
+    });
+
+    // 宛先のホスト名はLambdaの実行環境内では `sandbox.localdomain` でなければならない
+    let body = serde_json::json!({
+        "schemaVersion": "2022-12-13",
+        "types": ["platform"],
+        "buffering": { "maxItems": 1000, "maxBytes": 262144, "timeoutMs": 25 },
+        "destination": {
+            "protocol": "HTTP",
+            "URI": format!("http://sandbox.localdomain:{}", CONFIG.telemetry_listener_port),
+        },
+    });
+    let result = client
+        .put(format!("http://{}/2022-07-01/telemetry", runtime_api))
+        .header(EXTENSION_ID_HEADER, extension_id)
+        .body(body.to_string())
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    match result {
+        Ok(_) => {
+            SUBSCRIBED.store(true, Ordering::Relaxed);
+            info!("subscribed telemetry api")
+        }
+        Err(e) => warn!("failed to subscribe telemetry api: {:?}", e),
+    }
+}
+
+/// コールドスタートの呼び出しのRootSpanにセットするメトリクス(`platform.initReport` の値)を取り出す。
+/// それ以外の呼び出しでは空
+pub(crate) fn take_init_metrics(cold_start: bool) -> Vec<(&'static str, f64)> {
+    if !cold_start {
+        return vec![];
+    }
+    std::mem::take(&mut *INIT_METRICS.lock().unwrap())
+}
+
+/// RootSpanをクローズ時に送らずに、`platform.runtimeDone` / `platform.report` が届くまで預かる。
+/// ハンドラの処理が終わった後、RootSpanをクローズする直前に呼ぶ。Telemetry APIを購読していなければ何もしない。
+/// 前回までの呼び出しで `platform.report` が届かなかったRootSpanは、ここでそのまま送る。
+pub(crate) fn defer_root_span(lambda_ctx: &LambdaContext, invocation: &Invocation, span: &Span) {
+    if !SUBSCRIBED.load(Ordering::Relaxed) {
+        return;
+    }
+    let pending = PendingInvocation {
+        span: PendingSpan::take(span),
+        tags: enhanced_metrics::tags(&FunctionMeta::from_context(lambda_ctx), invocation.cold_start),
+    };
+    let stale: Vec<PendingInvocation> = {
+        let mut map = PENDING.lock().unwrap();
+        let stale = map.drain().map(|(_, p)| p).collect();
+        map.insert(lambda_ctx.request_id.clone(), pending);
+        stale
+    };
+    stale.into_iter().filter_map(|p| p.span).for_each(PendingSpan::export);
+}
+
+async fn receive(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let mut exported = false;
+    match hyper::body::to_bytes(req.into_body()).await {
+        Ok(bytes) => match serde_json::from_slice::<Vec<TelemetryEvent>>(&bytes) {
+            Ok(events) => {
+                for event in events {
+                    exported |= handle_event(event);
+                }
+            }
+            Err(e) => warn!("failed to parse telemetry events: {:?}", e),
+        },
+        Err(e) => warn!("failed to read telemetry events: {:?}", e),
+    }
+    // 呼び出しの終了時のフラッシュには間に合っていないので、ここで送り切る
+    if exported {
+        helper::flush_all().await;
+    }
+    Ok(Response::new(Body::empty()))
+}
+
+/// レコードを処理する。預かっていたRootSpanを送った場合はtrue
+fn handle_event(event: TelemetryEvent) -> bool {
+    let record = PlatformRecord::deserialize(&event.record).unwrap_or_default();
+    match event.event_type.as_str() {
+        "platform.initReport" => {
+            let init = collect(&[("platform.init_report.duration_ms", record.metrics.duration_ms)]);
+            INIT_METRICS.lock().unwrap().extend(init);
+            false
+        }
+        "platform.runtimeDone" => {
+            let mut pending = PENDING.lock().unwrap();
+            if let Some(span) = record_span(&mut pending, &record) {
+                set_record(
+                    span,
+                    &record,
+                    "platform.runtime_done",
+                    &[
+                        ("duration_ms", record.metrics.duration_ms),
+                        ("produced_bytes", record.metrics.produced_bytes),
+                    ],
+                );
+            }
+            false
+        }
+        "platform.report" => {
+            let pending = record
+                .request_id
+                .as_ref()
+                .and_then(|id| PENDING.lock().unwrap().remove(id));
+            let tags = match &pending {
+                Some(p) => p.tags.clone(),
+                None => default_tags(),
+            };
+            submit_report_metrics(&record, &tags);
+            match pending.and_then(|p| p.span) {
+                Some(mut span) => {
+                    set_record(
+                        &mut span,
+                        &record,
+                        "platform.report",
+                        &[
+                            ("duration_ms", record.metrics.duration_ms),
+                            ("billed_duration_ms", record.metrics.billed_duration_ms),
+                            ("memory_size_mb", record.metrics.memory_size_mb),
+                            ("max_memory_used_mb", record.metrics.max_memory_used_mb),
+                        ],
+                    );
+                    span.export();
+                    true
+                }
+                None => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// レコードのリクエストIDに対応する、預かっているRootSpan
+fn record_span<'a>(
+    pending: &'a mut HashMap<String, PendingInvocation>, record: &PlatformRecord,
+) -> Option<&'a mut PendingSpan> {
+    pending.get_mut(record.request_id.as_deref()?)?.span.as_mut()
+}
+
+/// レコードのステータス・エラー種別をタグに、計測値をメトリクスとしてRootSpanにセットする。キーは `{prefix}.{名前}`
+fn set_record(span: &mut PendingSpan, record: &PlatformRecord, prefix: &str, metrics: &[(&'static str, Option<f64>)]) {
+    if let Some(status) = &record.status {
+        span.set_tag(&format!("{}.status", prefix), status);
+    }
+    if let Some(error_type) = &record.error_type {
+        span.set_tag(&format!("{}.error_type", prefix), error_type);
+    }
+    for (key, value) in collect(metrics) {
+        span.set_metric(&format!("{}.{}", prefix, key), value);
+    }
+}
+
+fn collect(metrics: &[(&'static str, Option<f64>)]) -> Vec<(&'static str, f64)> {
+    metrics.iter().filter_map(|(k, v)| v.map(|v| (*k, v))).collect()
+}
+
+/// 預かっている呼び出しが無い場合の拡張メトリクスのタグ。関数名とリージョンのみ
+fn default_tags() -> Vec<String> {
+    let function_name = env::var("AWS_LAMBDA_FUNCTION_NAME").unwrap_or_default().to_lowercase();
+    let region = env::var("AWS_REGION").unwrap_or_default();
+    vec![format!("functionname:{}", function_name), format!("region:{}", region)]
+}
+
+/// `platform.report` の値を拡張メトリクスとして送る。
+/// 実際のタイムアウトやメモリ不足はプロセス内では検知できないので、ここで送る
+fn submit_report_metrics(record: &PlatformRecord, tags: &[String]) {
+    let m = &record.metrics;
+    if !CONFIG.enhanced_metrics {
+        return;
+    }
+    let tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+    // タイムアウト直前を検知して送信済みなら数えない
+    let timeout_submitted = record
+        .request_id
+        .as_deref()
+        .map_or(false, enhanced_metrics::take_timeout_submitted);
+    if record.status.as_deref() == Some("timeout") && !timeout_submitted {
+        metrics::send_distribution_metric("aws.lambda.enhanced.timeouts", 1.0, &tags);
+    }
+    if record.error_type.as_deref() == Some("Runtime.OutOfMemory") {
+        metrics::send_distribution_metric("aws.lambda.enhanced.out_of_memory", 1.0, &tags);
+    }
+    if let Some(v) = m.billed_duration_ms {
+        metrics::send_distribution_metric("aws.lambda.enhanced.billed_duration", v / 1000.0, &tags);
+    }
+    if let Some(v) = m.max_memory_used_mb {
+        metrics::send_distribution_metric("aws.lambda.enhanced.max_memory_used", v, &tags);
+    }
+}
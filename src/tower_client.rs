@@ -0,0 +1,75 @@
+//! tower対応のHTTPクライアント(hyperなど)向けのトレース処理。
+//!
+//! [helper::request_http](crate::helper::request_http) はreqwest専用なので、
+//! `http::Request` / `http::Response` を扱う任意のtowerの `Service` に被せられる [DatadogClientLayer] を用意する。
+//! 処理内容は `request_http` と同じで、`http.request` のSpan作成、トレーシング用ヘッダの挿入、ステータス・エラーの反映を行う。
+//!
+//! ## Example
+//! ```
+//! let client = tower::ServiceBuilder::new()
+//!     .layer(DatadogClientLayer)
+//!     .service(hyper::Client::new());
+//! let res = client.oneshot(req).await?;
+//! ```
+
+use crate::header_tags;
+use crate::helper;
+use lambda_http::http::{Request, Response};
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tracing::Instrument;
+
+/// クライアントの `Service` にトレース処理を被せる `Layer`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DatadogClientLayer;
+
+impl<S> Layer<S> for DatadogClientLayer {
+    type Service = DatadogClientService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        DatadogClientService { inner }
+    }
+}
+
+/// [DatadogClientLayer] で被せた `Service`
+#[derive(Clone, Debug)]
+pub struct DatadogClientService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for DatadogClientService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Display,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let span = helper::client_span("http.request", req.method().as_str(), &req.uri().to_string());
+        header_tags::set_request_tags(&span, req.headers());
+        helper::inject_headers(&span, req.headers_mut());
+        let fut = {
+            let _enter = span.enter();
+            self.inner.call(req)
+        };
+
+        Box::pin(async move {
+            let result = fut.instrument(span.clone()).await;
+            match &result {
+                Ok(res) => helper::record_client_response(&span, res.status().as_u16(), res.headers()),
+                Err(err) => helper::record_client_error(&span, &err.to_string()),
+            }
+            result
+        })
+    }
+}
@@ -0,0 +1,154 @@
+//! イベントの型毎に、トレースに必要な情報の取り出し方を定義する。
+//!
+//! `handle_event_with_trace` は [TraceExtractor] を実装した型であれば何でも受け付ける。
+//! AWSの各種イベント(API Gateway/DynamoDB Streams/S3)と、任意のJSONペイロード(`serde_json::Value`)向けの実装を用意している。
+//! 独自のイベント型を使う場合は、その型に [TraceExtractor] を実装する。
+//!
+//! ## Example
+//! ```
+//! #[derive(Deserialize)]
+//! struct OrderEvent {
+//!     order_id: String,
+//!     _datadog: Option<HashMap<String, String>>,
+//! }
+//!
+//! impl TraceExtractor for OrderEvent {
+//!     fn trace_context(&self) -> Option<TraceContext> {
+//!         self._datadog.as_ref().and_then(TraceContext::from_map)
+//!     }
+//!     fn resource(&self) -> Option<String> {
+//!         Some("order".to_string())
+//!     }
+//!     fn tags(&self) -> Vec<(&'static str, String)> {
+//!         vec![("order_id", self.order_id.clone())]
+//!     }
+//! }
+//! ```
+
+use crate::inferred_span::InferredSpan;
+use crate::step_functions::StepFunctionsContext;
+use aws_lambda_events::event::apigw::ApiGatewayProxyRequest;
+use aws_lambda_events::event::dynamodb;
+use aws_lambda_events::event::s3::S3Event;
+use lambda_http::http::HeaderMap;
+use std::collections::HashMap;
+
+const DD_TRACE_ID_HEADER: &str = "x-datadog-trace-id";
+const DD_PARENT_ID_HEADER: &str = "x-datadog-parent-id";
+
+/// 呼び出し元から引き継ぐトレース情報
+#[derive(Copy, Clone, Debug)]
+pub struct TraceContext {
+    pub trace_id: u64,
+    /// 0の場合は親無し
+    pub parent_id: u64,
+}
+
+impl TraceContext {
+    /// `x-datadog-trace-id` 等のキーを持つMapから取り出す。トレースIDが無ければNone
+    pub fn from_map(map: &HashMap<String, String>) -> Option<Self> {
+        Self::from_lookup(|key| map.get(key).map(String::as_str))
+    }
+
+    /// HTTPヘッダから取り出す。トレースIDが無ければNone
+    pub fn from_header_map(headers: &HeaderMap) -> Option<Self> {
+        Self::from_lookup(|key| headers.get(key).and_then(|v| v.to_str().ok()))
+    }
+
+    /// JSONペイロードの `_datadog` もしくは `headers` から取り出す。トレースIDが無ければNone
+    pub fn from_json(payload: &serde_json::Value) -> Option<Self> {
+        ["_datadog", "headers"].iter().find_map(|carrier| {
+            let carrier = payload.get(carrier)?;
+            Self::from_lookup(|key| carrier.get(key).and_then(|v| v.as_str()))
+        })
+    }
+
+    fn from_lookup<'a>(lookup: impl Fn(&str) -> Option<&'a str>) -> Option<Self> {
+        let trace_id = lookup(DD_TRACE_ID_HEADER)?.parse::<u64>().ok()?;
+        let parent_id = lookup(DD_PARENT_ID_HEADER)
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        Some(TraceContext { trace_id, parent_id })
+    }
+}
+
+/// イベントからトレースに必要な情報を取り出すためのトレイト。
+/// 全てのメソッドにデフォルト実装があるので、必要なものだけ実装すれば良い。
+pub trait TraceExtractor {
+    /// 呼び出し元から引き継ぐトレース情報。Noneの場合はトレースIDを新規採番する
+    fn trace_context(&self) -> Option<TraceContext> {
+        None
+    }
+
+    /// RootSpanのresource。Noneの場合は関数名になる
+    fn resource(&self) -> Option<String> {
+        None
+    }
+
+    /// RootSpanの `span.kind`
+    fn span_kind(&self) -> &'static str {
+        "server"
+    }
+
+    /// RootSpanにセットするタグ
+    fn tags(&self) -> Vec<(&'static str, String)> {
+        vec![]
+    }
+
+    /// RootSpanの親として作成する推論Span。先頭のSpanがRootSpanの親になる
+    fn inferred_spans(&self) -> Vec<InferredSpan> {
+        vec![]
+    }
+}
+
+impl TraceExtractor for ApiGatewayProxyRequest {
+    fn trace_context(&self) -> Option<TraceContext> {
+        TraceContext::from_header_map(&self.headers)
+    }
+
+    fn tags(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("http.method", self.http_method.to_string()),
+            ("http.url", self.path.clone().unwrap_or_default()),
+        ]
+    }
+
+    fn inferred_spans(&self) -> Vec<InferredSpan> {
+        InferredSpan::from_apigw_rest_context(&self.request_context)
+            .into_iter()
+            .collect()
+    }
+}
+
+impl TraceExtractor for dynamodb::Event {
+    fn inferred_spans(&self) -> Vec<InferredSpan> {
+        InferredSpan::from_dynamodb_event(self).into_iter().collect()
+    }
+}
+
+impl TraceExtractor for S3Event {
+    fn inferred_spans(&self) -> Vec<InferredSpan> {
+        InferredSpan::from_s3_event(self)
+    }
+}
+
+/// 任意のJSONペイロード。
+/// Step FunctionsのContextオブジェクトが含まれていればそこからトレースIDを算出し、
+/// そうでなければ `_datadog` もしくは `headers` に含まれるトレースヘッダを引き継ぐ。
+impl TraceExtractor for serde_json::Value {
+    fn trace_context(&self) -> Option<TraceContext> {
+        match StepFunctionsContext::from_payload(self) {
+            Some(sfn) => Some(TraceContext {
+                trace_id: sfn.trace_id(),
+                parent_id: sfn.parent_id(),
+            }),
+            None => TraceContext::from_json(self),
+        }
+    }
+
+    fn tags(&self) -> Vec<(&'static str, String)> {
+        StepFunctionsContext::from_payload(self)
+            .map(|sfn| sfn.tags())
+            .unwrap_or_default()
+    }
+}
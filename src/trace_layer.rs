@@ -0,0 +1,68 @@
+//! lambda_httpのサービス向けのトレース処理を `Layer` として提供する。
+//!
+//! [helper::handle_request_with_trace](crate::helper::handle_request_with_trace) をクロージャで被せる代わりに、
+//! `Service<Request>` であれば何にでも(axumのRouterなども)被せられる [DatadogTraceLayer] を用意する。
+//! 処理内容は `handle_request_with_trace` と同じで、RootSpanの作成と処理結果の反映を行う。
+//!
+//! ## Example
+//! ```
+//! let app = axum::Router::new().route("/", get(root));
+//! let service = tower::ServiceBuilder::new().layer(DatadogTraceLayer).service(app);
+//! lambda_http::run(service).await?;
+//! ```
+
+use crate::helper;
+use lambda_http::{Request, Response};
+use lambda_runtime::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+/// ハンドラの `Service` にトレース処理を被せる `Layer`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DatadogTraceLayer;
+
+impl<S> Layer<S> for DatadogTraceLayer {
+    type Service = DatadogTraceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        DatadogTraceService { inner }
+    }
+}
+
+/// [DatadogTraceLayer] で被せた `Service`
+#[derive(Clone, Debug)]
+pub struct DatadogTraceService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request> for DatadogTraceService<S>
+where
+    S: Service<Request, Response = Response<B>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<Error>,
+    B: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = Error;
+    // lambda_http::run はSendなFutureを要求する
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // poll_readyで準備ができたのは self.inner なので、それを持ち出して代わりにクローンを置いておく
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            helper::handle_request_with_trace(req, move |req| {
+                let fut = inner.call(req);
+                async move { fut.await.map_err(Into::into) }
+            })
+            .await
+        })
+    }
+}
@@ -0,0 +1,50 @@
+//! 全てのリクエストを自動でトレースするHTTPクライアント。
+//!
+//! [helper::request_http](crate::helper::request_http) は呼び出し側が毎回それを経由させる必要があり、
+//! 直接 `client.execute(..)` するとトレースから漏れてしまう。
+//! [TracedClient] はreqwest-middlewareでクライアント自体にトレース処理を組み込むので、
+//! このクライアントから送ったリクエストは全て `reqwest.http` のSpanとして記録され、トレーシング用ヘッダも挿入される。
+//!
+//! ## Example
+//! ```
+//! let client = traced_client::new_client(reqwest::Client::new());
+//! let res = client.get("https://www.google.com").send().await?;
+//! ```
+
+use crate::header_tags;
+use crate::helper;
+use async_trait::async_trait;
+use reqwest::{Request, Response};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Middleware, Next};
+use task_local_extensions::Extensions;
+use tracing::Instrument;
+
+/// トレース処理を組み込んだクライアント
+pub type TracedClient = ClientWithMiddleware;
+
+/// 与えられたクライアントに [TracingMiddleware] を組み込む
+pub fn new_client(client: reqwest::Client) -> TracedClient {
+    ClientBuilder::new(client).with(TracingMiddleware).build()
+}
+
+/// リクエスト毎にSpanを作成し、トレーシング用ヘッダの挿入と、処理結果のSpanへの反映を行うミドルウェア
+pub struct TracingMiddleware;
+
+#[async_trait]
+impl Middleware for TracingMiddleware {
+    async fn handle(
+        &self, mut req: Request, extensions: &mut Extensions, next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let span = helper::client_span("reqwest.http", req.method().as_str(), req.url().as_str());
+        header_tags::set_request_tags(&span, req.headers());
+        helper::inject_headers(&span, req.headers_mut());
+
+        // async_traitではEnteredを保持したままawaitできないので、instrumentでSpanに入る
+        let result = next.run(req, extensions).instrument(span.clone()).await;
+        match &result {
+            Ok(res) => helper::record_client_response(&span, res.status().as_u16(), res.headers()),
+            Err(err) => helper::record_client_error(&span, &err.to_string()),
+        }
+        result
+    }
+}